@@ -0,0 +1,346 @@
+use crate::{
+    bf::{interpreter::MEM_SIZE, AsInst, Extensions, Instruction, Program},
+    check_brackets, Error, Position, RuntimeError,
+};
+
+/// How many instructions are simulated before the analysis stops following loops.
+const BUDGET: u64 = 100_000;
+
+/// Result of [`input_demand`]: how many bytes of input a program reads.
+///
+/// [`input_demand`]: fn.input_demand.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputDemand {
+    /// Bytes read by every run that finishes.
+    pub min: usize,
+    /// Most bytes a run can read, `None` when it depends on the input or the analysis couldn't
+    /// bound it.
+    pub max: Option<usize>,
+}
+
+/// Finds how many bytes of input the program needs without running it.
+///
+/// The program is simulated for as long as the tape doesn't depend on the input, reads in
+/// loops that never run don't count.
+/// ```
+/// # use bf_lib::{input_demand, InputDemand};
+/// let demand = input_demand("++[>,<-] [ skipped, ]").unwrap();
+/// assert_eq!(demand, InputDemand { min: 2, max: Some(2) });
+///
+/// let demand = input_demand(",[.,]").unwrap();
+/// assert_eq!(demand, InputDemand { min: 1, max: None });
+/// ```
+pub fn input_demand(program: &str) -> Result<InputDemand, Error> {
+    check_brackets(program)?;
+    let prog = program.to_inst(&Extensions::default())?;
+    let walker = Walker::run(&prog);
+    Ok(InputDemand {
+        min: walker.reads.len(),
+        max: if walker.bounded {
+            Some(walker.reads.len())
+        } else {
+            None
+        },
+    })
+}
+
+/// Result of [`memory_bounds`]: where the tape pointer can go.
+///
+/// [`memory_bounds`]: fn.memory_bounds.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryBounds {
+    /// Number of cells from the start of the tape the pointer can reach, `None` when a loop can
+    /// move it right without bound.
+    pub extent: Option<usize>,
+    /// First move that can take the pointer left of cell 0.
+    pub underflow: Option<Position>,
+}
+
+/// Finds how far the tape pointer can go without running the program.
+///
+/// Loops with no net movement put the pointer at the same cell on every iteration, so their
+/// moves are followed exactly. Loops that do move it, like `[>]`, can run any number of times.
+/// ```
+/// # use bf_lib::{memory_bounds, MemoryBounds, Position};
+/// let bounds = memory_bounds("+[>++>+<<-]>>>").unwrap();
+/// assert_eq!(bounds, MemoryBounds { extent: Some(4), underflow: None });
+///
+/// let bounds = memory_bounds(">+[<]").unwrap();
+/// assert_eq!(bounds.extent, Some(2));
+/// assert_eq!(bounds.underflow, Some(Position { offset: 3, line: 1, column: 4 }));
+/// ```
+pub fn memory_bounds(program: &str) -> Result<MemoryBounds, Error> {
+    check_brackets(program)?;
+    let prog = program.to_inst(&Extensions::default())?;
+    let bounds = Bounds::run(&prog);
+    Ok(MemoryBounds {
+        extent: if bounds.highest == i64::MAX {
+            None
+        } else {
+            Some(bounds.highest as usize + 1)
+        },
+        underflow: bounds
+            .underflow
+            .map(|pc| Position::new(program, prog.spans[pc].start)),
+    })
+}
+
+/// Which instructions are moves proven to stay on the tape, when starting from cell 0.
+pub(crate) fn safe_moves(prog: &Program) -> Vec<bool> {
+    Bounds::run(prog).safe
+}
+
+/// Fails if `input` bytes aren't enough for the program to finish, pointing at the first read
+/// that would fail.
+///
+/// Only reads that run even if the program never finishes count, so a program that would loop
+/// forever first still does.
+pub(crate) fn check_input(prog: &Program, source: &str, input: usize) -> Result<(), Error> {
+    let walker = Walker::run(prog);
+    let certain = walker.certain.unwrap_or(walker.reads.len());
+    match walker.reads[..certain].get(input) {
+        Some(&pc) => Err(Error::Runtime(RuntimeError::InputTooShort(Position::new(
+            source,
+            prog.spans[pc].start,
+        )))),
+        None => Ok(()),
+    }
+}
+
+/// What the analysis knows about the tape.
+enum Tape {
+    /// Value of every cell, `None` once it depends on the input.
+    Known { cells: Vec<Option<u8>>, ptr: usize },
+    /// The pointer is unknown, `zero` tells if the current cell is known to be zero.
+    Lost { zero: bool },
+}
+
+impl Tape {
+    fn cell(&self) -> Option<u8> {
+        match self {
+            Tape::Known { cells, ptr } => cells[*ptr],
+            Tape::Lost { zero: true } => Some(0),
+            Tape::Lost { zero: false } => None,
+        }
+    }
+
+    fn update(&mut self, f: impl FnOnce(u8) -> Option<u8>) {
+        match self {
+            Tape::Known { cells, ptr } => cells[*ptr] = cells[*ptr].and_then(f),
+            Tape::Lost { zero } => *zero = false,
+        }
+    }
+
+    /// Moves the pointer, returns false if it surely leaves the tape.
+    fn shift(&mut self, by: isize) -> bool {
+        match self {
+            Tape::Known { cells, ptr } => {
+                let to = *ptr as isize + by;
+                if to < 0 || to >= MEM_SIZE as isize {
+                    return false;
+                }
+                *ptr = to as usize;
+                if cells.len() <= *ptr {
+                    cells.resize(*ptr + 1, Some(0))
+                }
+            }
+            Tape::Lost { zero } => *zero = false,
+        }
+        true
+    }
+}
+
+/// Follows the instructions every finishing run executes.
+struct Walker<'a> {
+    insts: &'a [Instruction],
+    tape: Tape,
+    budget: u64,
+    /// Index of every read that's surely executed, in order
+    reads: Vec<usize>,
+    /// Number of reads before the first loop or call the walker couldn't follow, the others
+    /// only run if it finishes
+    certain: Option<usize>,
+    /// Whether no read can run an unknown number of times
+    bounded: bool,
+}
+
+impl Walker<'_> {
+    fn run(prog: &Program) -> Walker<'_> {
+        let mut walker = Walker {
+            insts: &prog.insts,
+            tape: Tape::Known {
+                cells: vec![Some(0)],
+                ptr: 0,
+            },
+            budget: BUDGET,
+            reads: Vec::new(),
+            certain: None,
+            bounded: true,
+        };
+        walker.walk();
+        walker
+    }
+
+    fn walk(&mut self) {
+        let mut p = 0;
+        while p < self.insts.len() {
+            let known = self.budget > 0;
+            match self.insts[p] {
+                Instruction::Add(x) => self.tape.update(|v| Some(v.wrapping_add(x as u8))),
+                Instruction::Sub(x) => self.tape.update(|v| Some(v.wrapping_sub(x as u8))),
+                Instruction::Right(x) | Instruction::Left(x) => {
+                    let by = if let Instruction::Left(_) = self.insts[p] {
+                        -(x as isize)
+                    } else {
+                        x as isize
+                    };
+                    // the program fails here, nothing after it runs
+                    if !self.tape.shift(by) {
+                        return;
+                    }
+                }
+                Instruction::Read => {
+                    self.reads.push(p);
+                    self.tape.update(|_| None)
+                }
+                Instruction::LoopStart(end) => match self.tape.cell() {
+                    Some(0) => p = end,
+                    Some(_) if known => (),
+                    _ => {
+                        self.skip(p, end);
+                        p = end
+                    }
+                },
+                Instruction::LoopEnd(start) => match self.tape.cell() {
+                    Some(0) => (),
+                    Some(_) if known => p = start,
+                    _ => self.skip(start, p),
+                },
+                // defining a procedure runs none of it
+                Instruction::ProcStart(end) => p = end,
+                // a call can do anything, like a loop that can't be followed, and so can the
+                // threads running next to this one
+                Instruction::Call | Instruction::Fork => {
+                    self.certain.get_or_insert(self.reads.len());
+                    self.bounded = false;
+                    self.tape = Tape::Lost { zero: false };
+                }
+                Instruction::Print | Instruction::Debug | Instruction::ProcEnd => (),
+            }
+            self.budget = self.budget.saturating_sub(1);
+            p += 1;
+        }
+    }
+
+    /// Gives up on the loop between `start` and `end`, it may run any number of times.
+    fn skip(&mut self, start: usize, end: usize) {
+        if self.insts[start..end]
+            .iter()
+            .any(|i| matches!(i, Instruction::Read | Instruction::Call))
+        {
+            self.bounded = false
+        }
+        self.certain.get_or_insert(self.reads.len());
+        self.tape = Tape::Lost { zero: true };
+    }
+}
+
+/// Follows the range of cells the pointer can be on, `i64::MIN` and `i64::MAX` stand for no
+/// bound.
+struct Bounds<'a> {
+    insts: &'a [Instruction],
+    /// Whether each instruction is a move that stays on the tape
+    safe: Vec<bool>,
+    highest: i64,
+    /// Index of the first move that can go left of cell 0
+    underflow: Option<usize>,
+}
+
+impl Bounds<'_> {
+    fn run(prog: &Program) -> Bounds<'_> {
+        let mut bounds = Bounds {
+            insts: &prog.insts,
+            safe: vec![false; prog.insts.len()],
+            highest: 0,
+            underflow: None,
+        };
+        bounds.walk(0, prog.insts.len(), 0, 0);
+        bounds
+    }
+
+    /// Goes through the instructions from `start` to `end`, with the pointer in `lo..=hi`.
+    fn walk(&mut self, start: usize, end: usize, mut lo: i64, mut hi: i64) {
+        let mut p = start;
+        while p < end {
+            match self.insts[p] {
+                Instruction::Right(x) => {
+                    lo = lo.saturating_add(x as i64);
+                    hi = hi.saturating_add(x as i64);
+                    self.safe[p] = hi < MEM_SIZE as i64;
+                    self.highest = self.highest.max(hi);
+                }
+                Instruction::Left(x) => {
+                    lo = lo.saturating_sub(x as i64);
+                    hi = hi.saturating_sub(x as i64);
+                    self.safe[p] = lo >= 0;
+                    if lo < 0 && self.underflow.is_none() {
+                        self.underflow = Some(p)
+                    }
+                }
+                Instruction::LoopStart(e) => {
+                    // where the pointer can be at the start of any iteration, and after the loop
+                    let (l, h) = match self.net(p + 1, e) {
+                        Some(0) => (lo, hi),
+                        Some(d) if d > 0 => (lo, i64::MAX),
+                        Some(_) => (i64::MIN, hi),
+                        None => (i64::MIN, i64::MAX),
+                    };
+                    self.walk(p + 1, e, l, h);
+                    lo = l;
+                    hi = h;
+                    p = e;
+                }
+                // procedures can be called with the pointer anywhere, and move it anywhere
+                Instruction::ProcStart(e) => {
+                    self.walk(p + 1, e, i64::MIN, i64::MAX);
+                    p = e;
+                }
+                Instruction::Call => {
+                    lo = i64::MIN;
+                    hi = i64::MAX;
+                }
+                // the new thread goes on one cell to the right
+                Instruction::Fork => {
+                    hi = hi.saturating_add(1);
+                    self.safe[p] = hi < MEM_SIZE as i64;
+                    self.highest = self.highest.max(hi);
+                }
+                _ => (),
+            }
+            p += 1;
+        }
+    }
+
+    /// Net movement of the instructions from `start` to `end`, `None` if it varies.
+    fn net(&self, start: usize, end: usize) -> Option<i64> {
+        let mut net = 0;
+        let mut p = start;
+        while p < end {
+            match self.insts[p] {
+                Instruction::Right(x) => net += x as i64,
+                Instruction::Left(x) => net -= x as i64,
+                Instruction::LoopStart(e) => {
+                    if self.net(p + 1, e) != Some(0) {
+                        return None;
+                    }
+                    p = e
+                }
+                Instruction::ProcStart(e) => p = e,
+                Instruction::Call | Instruction::Fork => return None,
+                _ => (),
+            }
+            p += 1;
+        }
+        Some(net)
+    }
+}
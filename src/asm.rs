@@ -0,0 +1,382 @@
+//! A small structured language compiling to brainfuck.
+//!
+//! Programs declare named cells and work on them with one statement per line, `#` starts a
+//! comment:
+//!
+//! ```text
+//! cell a b            # declares cells, in tape order
+//! set a 10            # a = 10
+//! add a -3            # a += -3, `sub a 3` does the same
+//! clear a             # a = 0
+//! move a b c          # b += a, c += a, a = 0
+//! read a              # a = next input byte
+//! print a             # prints the byte in a
+//! print "Hi!\n"       # prints a string, supports \n \t \\ \" escapes
+//! while a { ... }     # repeats while a isn't 0
+//! if a { ... }        # runs once if a isn't 0, a is kept
+//! ```
+//!
+//! The compiler uses the cells after the declared ones as scratch space.
+
+use crate::{Error, Position};
+
+/// Compiles a program to brainfuck text, accepted by [`Exec::prog`].
+///
+/// [`Exec::prog`]: ../struct.Exec.html#method.prog
+/// ```
+/// # use bf_lib::{asm, Exec};
+/// let prog = asm::compile(
+///     "cell n total
+///     set n 3
+///     while n {
+///         add total 2   # total = 2 * n
+///         sub n 1
+///     }
+///     if total { print \"six: \" }
+///     add total 48
+///     print total",
+/// )
+/// .unwrap();
+/// assert_eq!(Exec::prog(&prog).interpret().unwrap(), "six: 6");
+/// ```
+pub fn compile(source: &str) -> Result<String, Error> {
+    let tokens = lex(source)?;
+    let mut parser = Parser {
+        source,
+        tokens,
+        next: 0,
+        cells: Vec::new(),
+    };
+    let body = parser.block(false)?;
+    let mut compiler = Compiler {
+        out: String::new(),
+        ptr: 0,
+        temps: parser.cells.len(),
+    };
+    compiler.block(&body);
+    Ok(compiler.out)
+}
+
+fn error(source: &str, offset: usize, msg: String) -> Error {
+    Error::Asm(Position::new(source, offset), msg)
+}
+
+#[derive(Debug, PartialEq)]
+enum Tok {
+    Word(String),
+    Str(Vec<u8>),
+    Open,
+    Close,
+    Newline,
+}
+
+fn lex(source: &str) -> Result<Vec<(Tok, usize)>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '\n' => tokens.push((Tok::Newline, pos)),
+            '{' => tokens.push((Tok::Open, pos)),
+            '}' => tokens.push((Tok::Close, pos)),
+            '#' => {
+                while chars.peek().is_some_and(|(_, c)| *c != '\n') {
+                    chars.next();
+                }
+            }
+            '"' => {
+                let mut s = Vec::new();
+                loop {
+                    let c = match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => '\n',
+                            Some((_, 't')) => '\t',
+                            Some((_, c @ '\\')) | Some((_, c @ '"')) => c,
+                            Some((p, _)) => return Err(error(source, p, "unknown escape".into())),
+                            None => return Err(error(source, pos, "unclosed string".into())),
+                        },
+                        Some((_, c)) => c,
+                        None => return Err(error(source, pos, "unclosed string".into())),
+                    };
+                    let mut buf = [0; 4];
+                    s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                tokens.push((Tok::Str(s), pos))
+            }
+            c if c.is_whitespace() => (),
+            _ => {
+                let mut end = pos + c.len_utf8();
+                while let Some(&(p, c)) = chars.peek() {
+                    if c.is_whitespace() || "{}#\"".contains(c) {
+                        break;
+                    }
+                    end = p + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push((Tok::Word(source[pos..end].to_owned()), pos))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Index of a declared cell.
+type Cell = usize;
+
+enum Stmt {
+    Add(Cell, u8),
+    Clear(Cell),
+    Set(Cell, u8),
+    Move(Cell, Vec<Cell>),
+    Read(Cell),
+    Print(Cell),
+    PrintStr(Vec<u8>),
+    While(Cell, Vec<Stmt>),
+    If(Cell, Vec<Stmt>),
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Tok, usize)>,
+    next: usize,
+    /// Names of the declared cells
+    cells: Vec<String>,
+}
+
+impl Parser<'_> {
+    /// Offset of the next token, or the end of the source.
+    fn pos(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.source.len(), |t| t.1)
+    }
+
+    fn err<T>(&self, pos: usize, msg: String) -> Result<T, Error> {
+        Err(error(self.source, pos, msg))
+    }
+
+    /// Statements until the end of the source, or until `}` if `nested`.
+    fn block(&mut self, nested: bool) -> Result<Vec<Stmt>, Error> {
+        let mut body = Vec::new();
+        loop {
+            let pos = self.pos();
+            match self.tokens.get(self.next).map(|t| &t.0) {
+                None if nested => return self.err(pos, "expected `}`".into()),
+                None => return Ok(body),
+                Some(Tok::Close) if nested => {
+                    self.next += 1;
+                    return Ok(body);
+                }
+                Some(Tok::Newline) => self.next += 1,
+                Some(Tok::Word(_)) => {
+                    if let Some(stmt) = self.statement()? {
+                        body.push(stmt)
+                    }
+                }
+                Some(_) => return self.err(pos, "expected a statement".into()),
+            }
+        }
+    }
+
+    fn word(&mut self, what: &str) -> Result<(String, usize), Error> {
+        let pos = self.pos();
+        match self.tokens.get(self.next) {
+            Some((Tok::Word(w), _)) => {
+                self.next += 1;
+                Ok((w.clone(), pos))
+            }
+            _ => self.err(pos, format!("expected {}", what)),
+        }
+    }
+
+    fn cell(&mut self) -> Result<Cell, Error> {
+        let (name, pos) = self.word("a cell name")?;
+        match self.cells.iter().position(|c| *c == name) {
+            Some(c) => Ok(c),
+            None => self.err(pos, format!("unknown cell `{}`", name)),
+        }
+    }
+
+    /// A number, taken modulo 256 since the cells wrap around.
+    fn number(&mut self) -> Result<u8, Error> {
+        let (n, pos) = self.word("a number")?;
+        match n.parse::<i64>() {
+            Ok(n) => Ok(n.rem_euclid(256) as u8),
+            Err(_) => self.err(pos, format!("`{}` isn't a number", n)),
+        }
+    }
+
+    /// Checks that the statement ends here.
+    fn end(&mut self) -> Result<(), Error> {
+        match self.tokens.get(self.next).map(|t| &t.0) {
+            None | Some(Tok::Close) => Ok(()),
+            Some(Tok::Newline) => {
+                self.next += 1;
+                Ok(())
+            }
+            _ => self.err(self.pos(), "expected the end of the line".into()),
+        }
+    }
+
+    fn body(&mut self) -> Result<Vec<Stmt>, Error> {
+        match self.tokens.get(self.next) {
+            Some((Tok::Open, _)) => {
+                self.next += 1;
+                self.block(true)
+            }
+            _ => self.err(self.pos(), "expected `{`".into()),
+        }
+    }
+
+    /// Parses a statement, declarations give `None`.
+    fn statement(&mut self) -> Result<Option<Stmt>, Error> {
+        let (keyword, pos) = self.word("a statement")?;
+        let stmt = match keyword.as_str() {
+            "cell" => {
+                while let Some((Tok::Word(name), p)) = self.tokens.get(self.next) {
+                    if self.cells.contains(name) {
+                        return self.err(*p, format!("cell `{}` is already declared", name));
+                    }
+                    self.cells.push(name.clone());
+                    self.next += 1;
+                }
+                self.end()?;
+                return Ok(None);
+            }
+            "add" => Stmt::Add(self.cell()?, self.number()?),
+            "sub" => Stmt::Add(self.cell()?, self.number()?.wrapping_neg()),
+            "clear" => Stmt::Clear(self.cell()?),
+            "set" => Stmt::Set(self.cell()?, self.number()?),
+            "move" => {
+                let from = self.cell()?;
+                let mut to = Vec::new();
+                while let Some((Tok::Word(_), _)) = self.tokens.get(self.next) {
+                    to.push(self.cell()?)
+                }
+                if to.contains(&from) {
+                    return self.err(pos, "can't move a cell to itself".into());
+                }
+                Stmt::Move(from, to)
+            }
+            "read" => Stmt::Read(self.cell()?),
+            "print" => match self.tokens.get(self.next) {
+                Some((Tok::Str(s), _)) => {
+                    let s = s.clone();
+                    self.next += 1;
+                    Stmt::PrintStr(s)
+                }
+                _ => Stmt::Print(self.cell()?),
+            },
+            "while" => Stmt::While(self.cell()?, self.body()?),
+            "if" => Stmt::If(self.cell()?, self.body()?),
+            _ => return self.err(pos, format!("unknown statement `{}`", keyword)),
+        };
+        self.end()?;
+        Ok(Some(stmt))
+    }
+}
+
+struct Compiler {
+    out: String,
+    /// Cell the pointer is on, always known since loops start and end on the same cell
+    ptr: Cell,
+    /// First free scratch cell, they're used like a stack
+    temps: Cell,
+}
+
+impl Compiler {
+    fn goto(&mut self, c: Cell) {
+        if c > self.ptr {
+            self.out.push_str(&">".repeat(c - self.ptr))
+        } else {
+            self.out.push_str(&"<".repeat(self.ptr - c))
+        }
+        self.ptr = c
+    }
+
+    fn add(&mut self, c: Cell, n: u8) {
+        self.goto(c);
+        if n <= 128 {
+            self.out.push_str(&"+".repeat(n as usize))
+        } else {
+            self.out.push_str(&"-".repeat(n.wrapping_neg() as usize))
+        }
+    }
+
+    fn clear(&mut self, c: Cell) {
+        self.goto(c);
+        self.out.push_str("[-]")
+    }
+
+    /// Runs `body` while `c` isn't zero.
+    fn repeat(&mut self, c: Cell, body: impl FnOnce(&mut Compiler)) {
+        self.goto(c);
+        self.out.push('[');
+        body(self);
+        self.goto(c);
+        self.out.push(']');
+    }
+
+    fn block(&mut self, body: &[Stmt]) {
+        for stmt in body {
+            self.stmt(stmt)
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Add(c, n) => self.add(*c, *n),
+            Stmt::Clear(c) => self.clear(*c),
+            Stmt::Set(c, n) => {
+                self.clear(*c);
+                self.add(*c, *n)
+            }
+            Stmt::Move(from, to) => self.repeat(*from, |s| {
+                for t in to {
+                    s.add(*t, 1)
+                }
+                s.add(*from, 255)
+            }),
+            Stmt::Read(c) => {
+                self.goto(*c);
+                self.out.push(',')
+            }
+            Stmt::Print(c) => {
+                self.goto(*c);
+                self.out.push('.')
+            }
+            Stmt::PrintStr(text) => {
+                let t = self.temps;
+                let mut v = 0u8;
+                for &b in text {
+                    self.add(t, b.wrapping_sub(v));
+                    self.out.push('.');
+                    v = b
+                }
+                if v != 0 {
+                    self.clear(t)
+                }
+            }
+            Stmt::While(c, body) => self.repeat(*c, |s| s.block(body)),
+            Stmt::If(c, body) => {
+                let (t, u) = (self.temps, self.temps + 1);
+                self.temps += 2;
+                // copy `c` to `t` through `u`
+                self.repeat(*c, |s| {
+                    s.add(t, 1);
+                    s.add(u, 1);
+                    s.add(*c, 255)
+                });
+                self.repeat(u, |s| {
+                    s.add(*c, 1);
+                    s.add(u, 255)
+                });
+                self.repeat(t, |s| {
+                    s.block(body);
+                    s.clear(t)
+                });
+                self.temps -= 2;
+            }
+        }
+    }
+}
@@ -1,11 +1,13 @@
-use crate::Error;
+use crate::{Error, Position, Span};
 use subprocess::{Exec, NullFile};
 
 pub mod interpreter;
 
+pub mod threads;
+
 pub mod transpiler;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     Add(u32),
     Sub(u32),
@@ -13,8 +15,21 @@ pub enum Instruction {
     Right(u32),
     Print,
     Read,
-    LoopStart,
-    LoopEnd,
+    /// Jumps past the matching `LoopEnd` at the given index when the cell is zero.
+    LoopStart(usize),
+    /// Jumps back past the matching `LoopStart` at the given index when the cell is not zero.
+    LoopEnd(usize),
+    /// Dumps the pointer and the cells around it, only parsed with `Extensions::debug`.
+    Debug,
+    /// Defines the procedure named by the cell, then jumps past the matching `ProcEnd` at the
+    /// given index. Only parsed with `Extensions::procedures`, like the next two.
+    ProcStart(usize),
+    /// Returns from the procedure.
+    ProcEnd,
+    /// Calls the procedure named by the cell.
+    Call,
+    /// Starts a thread one cell to the right, only parsed with `Extensions::fork`.
+    Fork,
     //Clear,
     //Copy(i32),
     //Mult(i32, i32),
@@ -26,143 +41,211 @@ enum Prev {
     Add,
 }
 
-trait AsInst {
-    fn to_inst(&self) -> Result<Vec<Instruction>, Error>;
+/// Instructions of a parsed program, along with the part of the source each one came from.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub insts: Vec<Instruction>,
+    pub spans: Vec<Span>,
+}
+
+impl Program {
+    fn push(&mut self, inst: Instruction, span: Span) {
+        self.insts.push(inst);
+        self.spans.push(span);
+    }
+}
+
+/// Number of cells on each side of the pointer shown by the `#` instruction.
+pub const DUMP_RADIUS: usize = 4;
+
+/// Most procedure calls that can be running at once.
+pub const MAX_CALLS: usize = 1 << 20;
+
+/// Most threads a forking program can start.
+pub const MAX_THREADS: usize = 256;
+
+/// Non-standard instructions `firstpass` should recognize, everything else is a comment.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// `#` dumps the pointer and the cells around it
+    pub debug: bool,
+    /// `(` and `)` define a procedure, `:` calls it, as in pbrain
+    pub procedures: bool,
+    /// `Y` forks the running thread, as in Brainfork
+    pub fork: bool,
+}
+
+pub(crate) trait AsInst {
+    fn to_inst(&self, ext: &Extensions) -> Result<Program, Error>;
 }
 
 impl AsInst for str {
-    fn to_inst(&self) -> Result<Vec<Instruction>, Error> {
-        firstpass(self.as_bytes())
+    fn to_inst(&self, ext: &Extensions) -> Result<Program, Error> {
+        firstpass(self, commands(self, ext))
     }
 }
 
 impl AsInst for String {
-    fn to_inst(&self) -> Result<Vec<Instruction>, Error> {
-        firstpass(self.as_bytes())
+    fn to_inst(&self, ext: &Extensions) -> Result<Program, Error> {
+        firstpass(self, commands(self, ext))
     }
 }
 
-pub fn run(
-    program: &str,
-    input: Option<String>,
-    time: Option<std::time::Duration>,
-    tmp_path: Option<std::path::PathBuf>,
-) -> Result<String, crate::Error> {
+/// Parses the program of `cfg`, written in its dialect.
+pub fn parse(cfg: &crate::Exec) -> Result<Program, Error> {
+    firstpass(&cfg.program, cfg.dialect.tokens(&cfg.program, &cfg.ext)?)
+}
+
+pub fn run(cfg: crate::Exec) -> Result<String, crate::Error> {
+    if cfg.resume.is_some() || cfg.checkpoint.is_some() || cfg.ext.fork {
+        return interpreter::run(cfg);
+    }
     match Exec::cmd("rustc").stdout(NullFile).stderr(NullFile).join() {
-        Ok(_) => transpiler::run(program, input, time, tmp_path),
-        Err(_) => interpreter::run(program, input, time),
+        Ok(_) => transpiler::run(cfg),
+        Err(_) => interpreter::run(cfg),
     }
 }
 
-fn firstpass(bytes: &[u8]) -> Result<Vec<Instruction>, Error> {
+#[cfg(feature = "async")]
+pub async fn run_async(cfg: crate::Exec) -> Result<String, crate::Error> {
+    if cfg.resume.is_some() || cfg.checkpoint.is_some() || cfg.ext.fork {
+        return interpreter::run_async(cfg).await;
+    }
+    let rustc = tokio::process::Command::new("rustc")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await;
+    match rustc {
+        Ok(_) => transpiler::run_async(cfg).await,
+        Err(_) => interpreter::run_async(cfg).await,
+    }
+}
+
+/// Whether the byte is an instruction, everything else is a comment.
+pub(crate) fn is_command(b: u8, ext: &Extensions) -> bool {
+    match b {
+        b'+' | b'-' | b'<' | b'>' | b'.' | b',' | b'[' | b']' => true,
+        b'#' => ext.debug,
+        b'(' | b')' | b':' => ext.procedures,
+        b'Y' => ext.fork,
+        _ => false,
+    }
+}
+
+/// The brainfuck commands of `source`, along with where they are.
+pub(crate) fn commands(source: &str, ext: &Extensions) -> Vec<(Span, u8)> {
+    source
+        .bytes()
+        .enumerate()
+        .filter(|(_, b)| is_command(*b, ext))
+        .map(|(pos, b)| {
+            let span = Span {
+                start: pos,
+                end: pos + 1,
+            };
+            (span, b)
+        })
+        .collect()
+}
+
+/// Builds the instructions from the commands of `source`, in any dialect.
+fn firstpass(source: &str, tokens: Vec<(Span, u8)>) -> Result<Program, Error> {
     fn changed(prev: Prev, ac: i32, mc: i32) -> Instruction {
         match prev {
             Prev::Add => {
                 if ac >= 0 {
                     Instruction::Add(ac as u32)
                 } else {
-                    Instruction::Sub(ac.abs() as u32)
+                    Instruction::Sub(ac.unsigned_abs())
                 }
             }
             Prev::Move => {
                 if mc >= 0 {
                     Instruction::Right(mc as u32)
                 } else {
-                    Instruction::Left(mc.abs() as u32)
+                    Instruction::Left(mc.unsigned_abs())
                 }
             }
         }
     }
     let (mut ac, mut mc) = (0i32, 0i32);
     let mut prev = None;
-    let mut inst: Vec<Instruction> = vec![];
-    for b in bytes.iter() {
-        match b {
-            b'>' => {
-                if let Some(Prev::Move) = prev {
-                    mc += 1
-                } else {
-                    if let Some(p) = prev.take() {
-                        inst.push(changed(p, ac, mc))
-                    }
-                    ac = 0;
-                    mc = 1;
-                    prev = Some(Prev::Move)
-                }
-            }
-            b'<' => {
-                if let Some(Prev::Move) = prev {
-                    mc -= 1
-                } else {
-                    if let Some(p) = prev.take() {
-                        inst.push(changed(p, ac, mc))
-                    }
-                    ac = 0;
-                    mc = -1;
-                    prev = Some(Prev::Move)
-                }
-            }
-            b'+' => {
-                if let Some(Prev::Add) = prev {
-                    ac += 1
-                } else {
-                    if let Some(p) = prev.take() {
-                        inst.push(changed(p, ac, mc))
-                    }
-                    ac = 1;
-                    mc = 0;
-                    prev = Some(Prev::Add)
-                }
-            }
-            b'-' => {
-                if let Some(Prev::Add) = prev {
-                    ac -= 1
-                } else {
-                    if let Some(p) = prev.take() {
-                        inst.push(changed(p, ac, mc))
-                    }
-                    ac = -1;
-                    mc = 0;
-                    prev = Some(Prev::Add)
-                }
-            }
-            b'.' => {
+    let mut prog = Program::default();
+    // span of the pending run of `+-` or `<>`
+    let mut run = Span { start: 0, end: 0 };
+    // (instruction index, byte offset) of every unclosed bracket or parenthesis
+    let mut open: Vec<(usize, usize)> = vec![];
+    for (span, b) in tokens {
+        let kind = match b {
+            b'>' | b'<' => Prev::Move,
+            b'+' | b'-' => Prev::Add,
+            _ => {
                 if let Some(p) = prev.take() {
-                    inst.push(changed(p, ac, mc))
+                    prog.push(changed(p, ac, mc), run)
                 }
                 ac = 0;
                 mc = 0;
-                inst.push(Instruction::Print);
-            }
-            b',' => {
-                if let Some(p) = prev.take() {
-                    inst.push(changed(p, ac, mc))
-                }
-                ac = 0;
-                mc = 0;
-                inst.push(Instruction::Read);
-            }
-            b'[' => {
-                if let Some(p) = prev.take() {
-                    inst.push(changed(p, ac, mc))
+                match b {
+                    b'.' => prog.push(Instruction::Print, span),
+                    b',' => prog.push(Instruction::Read, span),
+                    b'#' => prog.push(Instruction::Debug, span),
+                    b':' => prog.push(Instruction::Call, span),
+                    b'Y' => prog.push(Instruction::Fork, span),
+                    b'[' | b'(' => {
+                        open.push((prog.insts.len(), span.start));
+                        let inst = if b == b'[' {
+                            Instruction::LoopStart(0)
+                        } else {
+                            Instruction::ProcStart(0)
+                        };
+                        prog.push(inst, span);
+                    }
+                    _ => {
+                        let end = prog.insts.len();
+                        let open = open.pop().map(|(start, _)| (start, prog.insts[start]));
+                        match (b, open) {
+                            (b']', Some((start, Instruction::LoopStart(_)))) => {
+                                prog.insts[start] = Instruction::LoopStart(end);
+                                prog.push(Instruction::LoopEnd(start), span);
+                            }
+                            (b')', Some((start, Instruction::ProcStart(_)))) => {
+                                prog.insts[start] = Instruction::ProcStart(end);
+                                prog.push(Instruction::ProcEnd, span);
+                            }
+                            _ => return Err(Error::Syntax(Position::new(source, span.start))),
+                        }
+                    }
                 }
-                ac = 0;
-                mc = 0;
-                inst.push(Instruction::LoopStart);
+                continue;
             }
-            b']' => {
-                if let Some(p) = prev.take() {
-                    inst.push(changed(p, ac, mc))
-                }
-                ac = 0;
-                mc = 0;
-                inst.push(Instruction::LoopEnd);
+        };
+        let same = matches!(
+            (&prev, &kind),
+            (Some(Prev::Move), Prev::Move) | (Some(Prev::Add), Prev::Add)
+        );
+        if !same {
+            if let Some(p) = prev.take() {
+                prog.push(changed(p, ac, mc), run)
             }
-            _ => (),
+            ac = 0;
+            mc = 0;
+            run.start = span.start;
+            prev = Some(kind);
         }
+        match b {
+            b'>' => mc += 1,
+            b'<' => mc -= 1,
+            b'+' => ac += 1,
+            _ => ac -= 1,
+        }
+        run.end = span.end;
     }
     if let Some(p) = prev.take() {
-        inst.push(changed(p, ac, mc))
+        prog.push(changed(p, ac, mc), run)
+    }
+    if let Some((_, pos)) = open.pop() {
+        return Err(Error::Syntax(Position::new(source, pos)));
     }
-    Ok(inst)
+    Ok(prog)
 }
@@ -1,166 +1,769 @@
 use crate::{
-    bf::{AsInst, Instruction},
-    check_brackets, Error, RuntimeError,
+    analysis::{check_input, safe_moves},
+    bf::{self, threads, Extensions, Instruction, Program, MAX_CALLS, MAX_THREADS},
+    profile::Counters,
+    CancelToken, Debugger, Dialect, Dump, DumpFn, Error, Exec, Position, Profile, RuntimeError,
+    Snapshot, Span,
 };
-use std::{collections::HashMap, num::Wrapping, sync::mpsc, thread, time::Duration};
-
-pub fn run(prog: &str, input: Option<String>, time: Option<Duration>) -> Result<String, Error> {
-    check_brackets(prog)?;
-    let insts = prog.to_inst()?;
-    let loops = maploops(&insts)?;
-    let output = if let Some(t) = time {
-        let (tx, rx) = mpsc::channel();
-        thread::spawn(move || {
-            thread::sleep(t);
-            tx.send(())
-        });
-        exec_timeout(insts, loops, input, rx)?
-    } else {
-        exec(insts, loops, input)?
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+/// Number of cells on the tape.
+pub const MEM_SIZE: usize = 30000;
+
+/// How many instructions are run between two checks of the stop conditions.
+const CHECK_INTERVAL: u64 = 10000;
+
+pub fn run(cfg: Exec) -> Result<String, Error> {
+    if cfg.real_threads && cfg.ext.fork {
+        return threads::run(cfg);
+    }
+    let (mut machine, mut stops) = setup(cfg)?;
+    exec(&mut machine, &mut stops)?;
+    Ok(machine.output)
+}
+
+#[cfg(feature = "async")]
+pub async fn run_async(cfg: Exec) -> Result<String, Error> {
+    if cfg.real_threads && cfg.ext.fork {
+        return tokio::task::spawn_blocking(move || threads::run(cfg))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+    }
+    let (mut machine, mut stops) = setup(cfg)?;
+    loop {
+        let budget = stops
+            .iter()
+            .map(|s| s.budget(&machine))
+            .min()
+            .unwrap_or(CHECK_INTERVAL)
+            .min(CHECK_INTERVAL);
+        if machine.run(budget)? {
+            return Ok(machine.output);
+        }
+        check(&mut machine, &mut stops)?;
+        tokio::task::yield_now().await;
+    }
+}
+
+pub fn profile(cfg: Exec) -> Result<Profile, Error> {
+    let source = cfg.program.clone();
+    let (mut machine, mut stops) = setup(cfg)?;
+    machine.profile = Some(Counters::new(machine.prog.insts.len()));
+    exec(&mut machine, &mut stops)?;
+    let counters = machine.profile.take().unwrap();
+    Ok(counters.into_profile(
+        &machine.prog.insts,
+        &machine.prog.spans,
+        source,
+        machine.output,
+    ))
+}
+
+pub fn debugger(cfg: Exec) -> Result<Debugger, Error> {
+    Ok(Debugger::new(machine(cfg)?.0))
+}
+
+/// Parses the program, returns the machine that will run it along with the rest of `cfg`.
+fn machine(mut cfg: Exec) -> Result<(Machine, Exec), Error> {
+    let prog = bf::parse(&cfg)?;
+    let mut machine = match cfg.resume.take() {
+        Some(snapshot) => Machine::restore(prog, snapshot)?,
+        None => Machine::new(prog, cfg.input.take()),
     };
-    Ok(output)
-}
-
-///Do a first pass on the program, adds every ['s position to a LIFO queue, pop from the
-///vector and add to a hashmap every time a ] is found.
-pub fn maploops(insts: &Vec<Instruction>) -> Result<HashMap<usize, usize>, Error> {
-    let mut map = HashMap::new();
-    let mut open: Vec<usize> = Vec::new();
-    for (i, b) in insts.iter().enumerate() {
-        match b {
-            Instruction::LoopStart => open.push(i),
-            Instruction::LoopEnd => {
-                let last = if let Some(last) = open.pop() {
-                    last
-                } else {
-                    return Err(Error::Syntax(i));
-                };
-                map.insert(last, i);
-                map.insert(i, last);
-            }
+    machine.source = cfg.program.clone();
+    machine.ext = cfg.ext;
+    machine.dialect = cfg.dialect.clone();
+    machine.checkpoint = cfg.checkpoint.take();
+    machine.on_debug = cfg.on_debug.take();
+    if let Some(path) = &cfg.trace {
+        machine.trace = Some(BufWriter::new(File::create(path).map_err(Error::Io)?));
+    }
+    Ok((machine, cfg))
+}
+
+/// Builds the machine and the stop conditions requested in `cfg`.
+fn setup(cfg: Exec) -> Result<(Machine, Vec<Box<dyn Stop>>), Error> {
+    let resuming = cfg.resume.is_some();
+    let (machine, cfg) = machine(cfg)?;
+    if !resuming {
+        check_input(&machine.prog, &machine.source, machine.input.len())?;
+    }
+    let mut stops: Vec<Box<dyn Stop>> = Vec::new();
+    if let Some(t) = cfg.time {
+        stops.push(Box::new(Deadline(Instant::now() + t)));
+    }
+    if let Some(n) = cfg.steps {
+        stops.push(Box::new(StepLimit(n)));
+    }
+    if let Some(token) = cfg.cancel {
+        stops.push(Box::new(token));
+    }
+    Ok((machine, stops))
+}
+
+/// State of a running program.
+pub struct Machine {
+    prog: Program,
+    mem: Vec<u8>,
+    /// Registers of the running thread
+    t: Thread,
+    input: Vec<u8>,
+    /// Input cursor
+    b: usize,
+    output: String,
+    steps: u64,
+    on_debug: Option<DumpFn>,
+    trace: Option<BufWriter<File>>,
+    profile: Option<Counters>,
+    /// How to undo each step, when recording
+    history: Option<Vec<Undo>>,
+    /// Needed to take a snapshot
+    source: String,
+    ext: Extensions,
+    dialect: Dialect,
+    /// Where to save a snapshot if the program is stopped
+    checkpoint: Option<PathBuf>,
+    /// Moves that can't leave the tape, skipping the bounds check
+    safe: Vec<bool>,
+    /// Index of the `(` of every defined procedure, by name
+    procs: Vec<Option<usize>>,
+    /// Every thread once the program has forked, the running one is only saved when switching
+    threads: Vec<Thread>,
+    /// Index of the running thread
+    thread: usize,
+    /// Pointer of the thread that ran the last instruction, right after it ran
+    stepped: usize,
+}
+
+/// Registers of a thread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Thread {
+    /// Instruction pointer
+    pub(crate) pc: usize,
+    /// Tape pointer
+    pub(crate) pointer: usize,
+    /// Index of the `:` of every running procedure call
+    pub(crate) calls: Vec<usize>,
+}
+
+impl Machine {
+    pub fn new(prog: Program, input: Option<String>) -> Machine {
+        Machine {
+            safe: safe_moves(&prog),
+            prog,
+            mem: vec![0; MEM_SIZE],
+            t: Thread::default(),
+            input: input.map(String::into_bytes).unwrap_or_default(),
+            b: 0,
+            output: String::new(),
+            steps: 0,
+            on_debug: None,
+            trace: None,
+            profile: None,
+            source: String::new(),
+            ext: Extensions::default(),
+            dialect: Dialect::default(),
+            checkpoint: None,
+            history: None,
+            procs: vec![None; 256],
+            threads: Vec::new(),
+            thread: 0,
+            stepped: 0,
+        }
+    }
+
+    /// Continues the run saved in `snapshot`, `prog` has to be its parsed source.
+    fn restore(prog: Program, snapshot: Snapshot) -> Result<Machine, Error> {
+        let inside = in_procedures(&prog);
+        if !valid(
+            &prog,
+            &inside,
+            snapshot.pc,
+            snapshot.pointer,
+            &snapshot.calls,
+        ) || snapshot
+            .procs
+            .iter()
+            .any(|&(_, start)| !matches!(prog.insts.get(start), Some(Instruction::ProcStart(_))))
+            || snapshot
+                .threads
+                .iter()
+                .any(|t| !valid(&prog, &inside, t.pc, t.pointer, &t.calls))
+            || (snapshot.thread > 0 && snapshot.thread >= snapshot.threads.len())
+            || snapshot.mem.len() > MEM_SIZE
+            || snapshot.cursor > snapshot.input.len()
+        {
+            return Err(Error::InvalidSnapshot);
+        }
+        let mut machine = Machine::new(prog, None);
+        // the analysis assumes the program starts at cell 0
+        machine.safe.iter_mut().for_each(|s| *s = false);
+        machine.mem = snapshot.mem;
+        machine.mem.resize(MEM_SIZE, 0);
+        machine.t.pointer = snapshot.pointer;
+        machine.stepped = snapshot.pointer;
+        machine.t.pc = snapshot.pc;
+        machine.input = snapshot.input;
+        machine.b = snapshot.cursor;
+        machine.output = snapshot.output;
+        machine.steps = snapshot.steps;
+        for (name, start) in snapshot.procs {
+            machine.procs[name as usize] = Some(start)
+        }
+        machine.t.calls = snapshot.calls;
+        machine.threads = snapshot.threads;
+        machine.thread = snapshot.thread;
+        Ok(machine)
+    }
+
+    /// Saves the current state of the program.
+    pub fn snapshot(&self) -> Snapshot {
+        let used = self.mem.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+        // the instructions are the same in brainfuck, which is all a snapshot can resume
+        let source = self.dialect.translate(&self.source, &self.ext).unwrap();
+        Snapshot {
+            source,
+            ext: self.ext,
+            mem: self.mem[..used].to_vec(),
+            pointer: self.t.pointer,
+            pc: self.t.pc,
+            input: self.input.clone(),
+            cursor: self.b,
+            output: self.output.clone(),
+            steps: self.steps,
+            procs: (0..=255u8)
+                .filter_map(|name| Some((name, self.procs[name as usize]?)))
+                .collect(),
+            calls: self.t.calls.clone(),
+            threads: self
+                .threads
+                .iter()
+                .enumerate()
+                .map(|(n, t)| if n == self.thread { &self.t } else { t }.clone())
+                .collect(),
+            thread: self.thread,
+        }
+    }
+
+    /// Position in the source of the next instruction.
+    fn position(&self) -> Position {
+        let offset = self.span().map_or(self.source.len(), |s| s.start);
+        Position::new(&self.source, offset)
+    }
+
+    /// Source span of the next instruction, `None` once the program has finished.
+    pub fn span(&self) -> Option<Span> {
+        self.prog.spans.get(self.t.pc).copied()
+    }
+
+    /// Index of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.t.pc
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.prog
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn pointer(&self) -> usize {
+        self.t.pointer
+    }
+
+    /// Pointer of the thread that ran the last instruction, as it left it: once the program has
+    /// forked, the running thread may have changed since.
+    pub fn stepped_pointer(&self) -> usize {
+        self.stepped
+    }
+
+    /// Number of input bytes read so far.
+    pub fn cursor(&self) -> usize {
+        self.b
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Number of instructions executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Starts or stops recording how to undo each step, stopping drops the recorded history.
+    pub fn record_history(&mut self, enabled: bool) {
+        match (enabled, &self.history) {
+            (true, None) => self.history = Some(Vec::new()),
+            (false, _) => self.history = None,
             _ => (),
         }
     }
-    if open.len() != 0 {
-        Err(Error::Syntax(open.pop().unwrap()))
-    } else {
-        Ok(map)
-    }
-}
-
-fn exec(
-    insts: Vec<Instruction>,
-    map: HashMap<usize, usize>,
-    input: Option<String>,
-) -> Result<String, Error> {
-    let mut mem = [Wrapping(0u8); 30000];
-    let (mut i, mut p, mut b) = (0usize, 0usize, 0usize);
-    let mut output = String::new();
-    let input = if let Some(a) = &input { a } else { "" };
-    while p < insts.len() {
-        match insts[p] {
-            Instruction::Right(x) => {
-                if i <= 30000 - x as usize {
-                    i += x as usize
-                } else {
-                    return Err(Error::Runtime(RuntimeError::OutOfMemoryBounds));
-                }
-            }
-            Instruction::Left(x) => {
-                if i >= x as usize {
-                    i -= x as usize
-                } else {
-                    return Err(Error::Runtime(RuntimeError::OutOfMemoryBounds));
+
+    /// Number of steps that can be undone.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Goes back to the state before the last step, returns false if it wasn't recorded.
+    pub fn undo(&mut self) -> bool {
+        match self.history.as_mut().and_then(Vec::pop) {
+            Some(u) => {
+                if u.thread != self.thread {
+                    self.switch(u.thread)
                 }
-            }
-            Instruction::Add(x) => mem[i] += Wrapping(x as u8),
-            Instruction::Sub(x) => mem[i] -= Wrapping(x as u8),
-            Instruction::Print => {
-                output.push(mem[i].0 as char);
-            }
-            Instruction::Read => {
-                mem[i] = {
-                    b += 1;
-                    if let Some(char) = input.as_bytes().get(b - 1) {
-                        Wrapping(*char)
-                    } else {
-                        return Err(Error::Runtime(RuntimeError::InputTooShort));
+                match self.prog.insts[u.pc] {
+                    Instruction::ProcStart(_) => self.procs[u.v as usize] = u.slot,
+                    Instruction::ProcEnd => self.t.calls.push(u.slot.unwrap()),
+                    Instruction::Call => {
+                        self.t.calls.pop();
+                    }
+                    Instruction::Fork => {
+                        self.threads.pop();
+                        self.mem[u.i + 1] = u.slot.unwrap() as u8;
                     }
+                    _ => (),
                 }
+                self.mem[u.i] = u.v;
+                self.t.pointer = u.i;
+                self.t.pc = u.pc;
+                self.b = u.b;
+                self.output.truncate(u.out);
+                self.steps -= 1;
+                true
             }
-            Instruction::LoopStart => {
-                if mem[i].0 == 0 {
-                    p = map[&p]
-                }
+            None => false,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.t.pc >= self.prog.insts.len()
+    }
+
+    /// Runs at most `budget` instructions, returns whether the program has finished.
+    ///
+    /// When an instruction fails the machine is left pointing at it.
+    pub fn run(&mut self, budget: u64) -> Result<bool, Error> {
+        if self.profile.is_some() || self.history.is_some() || self.trace.is_some() || self.ext.fork
+        {
+            self.run_instrumented(budget)
+        } else {
+            self.run_fast(budget)
+        }
+    }
+
+    /// Runs the instructions with nothing to record in between.
+    fn run_fast(&mut self, budget: u64) -> Result<bool, Error> {
+        let len = self.prog.insts.len();
+        let mut done = 0;
+        let (prog, safe, t, mut state) = self.parts();
+        // a local copy stays in registers
+        let mut thread = std::mem::take(t);
+        let res = loop {
+            if thread.pc >= len {
+                break Ok(true);
             }
-            Instruction::LoopEnd => {
-                if mem[i].0 != 0 {
-                    p = map[&p]
-                }
+            if done == budget {
+                break Ok(false);
+            }
+            if let Err(e) = step(prog, safe, &mut thread, &mut state) {
+                break Err(e);
+            }
+            done += 1;
+        };
+        *t = thread;
+        self.stepped = self.t.pointer;
+        self.steps += done;
+        res.map_err(|e| Error::Runtime(e(self.position())))
+    }
+
+    /// Runs the instructions one at a time, profiling, recording and scheduling threads.
+    fn run_instrumented(&mut self, budget: u64) -> Result<bool, Error> {
+        let mut done = 0;
+        while self.t.pc < self.prog.insts.len() {
+            if done == budget {
+                return Ok(false);
+            }
+            let (pc, i, v) = (self.t.pc, self.t.pointer, self.mem[self.t.pointer]);
+            let (b, out) = (self.b, self.output.len());
+            let (prog, safe, t, mut state) = self.parts();
+            let slot = match step(prog, safe, t, &mut state) {
+                Ok(slot) => slot,
+                Err(e) => return Err(Error::Runtime(e(self.position()))),
+            };
+            if let Some(c) = &mut self.profile {
+                c.record(pc, self.prog.insts[pc], self.t.pc != pc + 1)
+            }
+            self.steps += 1;
+            done += 1;
+            if let Some(h) = &mut self.history {
+                h.push(Undo {
+                    pc,
+                    i,
+                    v,
+                    b,
+                    out,
+                    slot,
+                    thread: self.thread,
+                })
+            }
+            if let Some(t) = &mut self.trace {
+                let span = self.prog.spans[pc];
+                let (j, w) = (self.t.pointer, self.mem[self.t.pointer]);
+                writeln!(t, "{} {} {} {} {} {}", span.start, span.end, i, v, j, w)
+                    .map_err(Error::Io)?;
+            }
+            self.stepped = self.t.pointer;
+            if !self.threads.is_empty() {
+                self.schedule()
             }
         }
-        p += 1
-    }
-    Ok(output)
-}
-
-fn exec_timeout(
-    insts: Vec<Instruction>,
-    map: HashMap<usize, usize>,
-    input: Option<String>,
-    rx: mpsc::Receiver<()>,
-) -> Result<String, Error> {
-    let mut mem = [Wrapping(0u8); 30000];
-    let (mut i, mut p, mut b) = (0usize, 0usize, 0usize);
-    let mut output = String::new();
-    let input = if let Some(a) = &input { a } else { "" };
-    while p < insts.len() {
-        match insts[p] {
-            Instruction::Right(x) => {
-                if i <= 30000 - x as usize {
-                    i += x as usize
-                } else {
-                    return Err(Error::Runtime(RuntimeError::OutOfMemoryBounds));
-                }
+        Ok(true)
+    }
+
+    /// Splits the machine into what [`step`] needs.
+    fn parts(&mut self) -> (&Program, &[bool], &mut Thread, Local<'_>) {
+        let state = Local {
+            mem: &mut self.mem,
+            input: &self.input,
+            b: &mut self.b,
+            output: &mut self.output,
+            on_debug: &mut self.on_debug,
+            procs: &mut self.procs,
+            threads: &mut self.threads,
+        };
+        (&self.prog, &self.safe, &mut self.t, state)
+    }
+
+    /// Gives the turn to the next thread that hasn't finished, the running one keeps it if it's
+    /// the only one left.
+    fn schedule(&mut self) {
+        let (n, len) = (self.threads.len(), self.prog.insts.len());
+        let next = (1..=n).map(|k| (self.thread + k) % n).find(|&t| {
+            let pc = if t == self.thread {
+                self.t.pc
+            } else {
+                self.threads[t].pc
+            };
+            pc < len
+        });
+        if let Some(t) = next.filter(|&t| t != self.thread) {
+            self.switch(t)
+        }
+    }
+
+    /// Saves the running thread and runs thread `t` instead.
+    fn switch(&mut self, t: usize) {
+        let next = std::mem::take(&mut self.threads[t]);
+        self.threads[self.thread] = std::mem::replace(&mut self.t, next);
+        self.thread = t;
+    }
+}
+
+/// What the instructions act on besides the registers of the thread running them.
+///
+/// The interpreter owns all of it, with real threads it's shared.
+pub(crate) trait State {
+    fn get(&self, i: usize) -> u8;
+    fn set(&mut self, i: usize, v: u8);
+    /// Takes the next input byte.
+    fn read(&mut self) -> Option<u8>;
+    fn print(&mut self, v: u8);
+    /// Runs the `#` starting at `offset` in the source.
+    fn dump(&mut self, offset: usize, pointer: usize);
+    fn procedure(&self, name: u8) -> Option<usize>;
+    /// Defines a procedure, returns the one with the same name it replaces.
+    fn define(&mut self, name: u8, start: usize) -> Option<usize>;
+    /// Makes room for one more thread, returns false if there would be too many.
+    fn add_thread(&mut self) -> bool;
+    /// Starts a thread forked by `Y`.
+    fn spawn(&mut self, thread: Thread);
+}
+
+/// The state of a [`Machine`], borrowed apart from its registers.
+struct Local<'a> {
+    mem: &'a mut [u8],
+    input: &'a [u8],
+    b: &'a mut usize,
+    output: &'a mut String,
+    on_debug: &'a mut Option<DumpFn>,
+    procs: &'a mut [Option<usize>],
+    threads: &'a mut Vec<Thread>,
+}
+
+impl State for Local<'_> {
+    fn get(&self, i: usize) -> u8 {
+        self.mem[i]
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        self.mem[i] = v
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let c = *self.input.get(*self.b)?;
+        *self.b += 1;
+        Some(c)
+    }
+
+    fn print(&mut self, v: u8) {
+        self.output.push(v as char)
+    }
+
+    fn dump(&mut self, offset: usize, pointer: usize) {
+        let dump = Dump::new(offset, pointer, self.mem);
+        match self.on_debug {
+            Some(f) => f(&dump),
+            None => eprintln!("{}", dump),
+        }
+    }
+
+    fn procedure(&self, name: u8) -> Option<usize> {
+        self.procs[name as usize]
+    }
+
+    fn define(&mut self, name: u8, start: usize) -> Option<usize> {
+        self.procs[name as usize].replace(start)
+    }
+
+    fn add_thread(&mut self) -> bool {
+        if self.threads.len() == MAX_THREADS {
+            return false;
+        }
+        if self.threads.is_empty() {
+            // room for the running thread
+            self.threads.push(Thread::default())
+        }
+        true
+    }
+
+    fn spawn(&mut self, thread: Thread) {
+        self.threads.push(thread)
+    }
+}
+
+/// Runs the instruction at `t.pc` and moves to the next one, returns the procedure, return address
+/// or cell it replaced.
+///
+/// An instruction that fails changes nothing, the error is built with its position.
+#[inline(always)]
+pub(crate) fn step<S: State>(
+    prog: &Program,
+    safe: &[bool],
+    t: &mut Thread,
+    s: &mut S,
+) -> Result<Option<usize>, fn(Position) -> RuntimeError> {
+    let (pc, i) = (t.pc, t.pointer);
+    let mut slot = None;
+    match prog.insts[pc] {
+        Instruction::Right(x) => {
+            if safe[pc] || i + (x as usize) < MEM_SIZE {
+                t.pointer += x as usize
+            } else {
+                return Err(RuntimeError::OutOfMemoryBounds);
             }
-            Instruction::Left(x) => {
-                if i >= x as usize {
-                    i -= x as usize
-                } else {
-                    return Err(Error::Runtime(RuntimeError::OutOfMemoryBounds));
-                }
+        }
+        Instruction::Left(x) => {
+            if safe[pc] || i >= x as usize {
+                t.pointer -= x as usize
+            } else {
+                return Err(RuntimeError::OutOfMemoryBounds);
             }
-            Instruction::Add(x) => mem[i] += Wrapping(x as u8),
-            Instruction::Sub(x) => mem[i] -= Wrapping(x as u8),
-            Instruction::Print => {
-                output.push(mem[i].0 as char);
-            }
-            Instruction::Read => {
-                mem[i] = {
-                    b += 1;
-                    if let Some(char) = input.as_bytes().get(b - 1) {
-                        Wrapping(*char)
-                    } else {
-                        return Err(Error::Runtime(RuntimeError::InputTooShort));
-                    }
-                }
+        }
+        Instruction::Add(x) => s.set(i, s.get(i).wrapping_add(x as u8)),
+        Instruction::Sub(x) => s.set(i, s.get(i).wrapping_sub(x as u8)),
+        Instruction::Print => s.print(s.get(i)),
+        Instruction::Read => match s.read() {
+            Some(c) => s.set(i, c),
+            None => return Err(RuntimeError::InputTooShort),
+        },
+        Instruction::LoopStart(end) => {
+            if s.get(i) == 0 {
+                t.pc = end
             }
-            Instruction::LoopStart => {
-                if mem[i].0 == 0 {
-                    p = map[&p]
-                }
+        }
+        Instruction::LoopEnd(start) => {
+            if s.get(i) != 0 {
+                t.pc = start
             }
-            Instruction::LoopEnd => {
-                if mem[i].0 != 0 {
-                    p = map[&p]
-                }
+        }
+        _ => slot = extension(prog, safe, t, s)?,
+    }
+    t.pc += 1;
+    Ok(slot)
+}
+
+/// Runs the instruction at `t.pc` from one of the extensions, kept apart so the common ones stay
+/// small.
+#[inline(never)]
+fn extension<S: State>(
+    prog: &Program,
+    safe: &[bool],
+    t: &mut Thread,
+    s: &mut S,
+) -> Result<Option<usize>, fn(Position) -> RuntimeError> {
+    let (pc, i) = (t.pc, t.pointer);
+    let mut slot = None;
+    match prog.insts[pc] {
+        Instruction::Debug => s.dump(prog.spans[pc].start, i),
+        Instruction::ProcStart(end) => {
+            slot = s.define(s.get(i), pc);
+            t.pc = end
+        }
+        Instruction::ProcEnd => {
+            let ret = t.calls.pop().unwrap();
+            slot = Some(ret);
+            t.pc = ret
+        }
+        Instruction::Call => match s.procedure(s.get(i)) {
+            Some(_) if t.calls.len() == MAX_CALLS => return Err(RuntimeError::StackOverflow),
+            Some(start) => {
+                t.calls.push(pc);
+                t.pc = start
+            }
+            None => return Err(RuntimeError::UndefinedProcedure),
+        },
+        Instruction::Fork => {
+            if !safe[pc] && i + 1 >= MEM_SIZE {
+                return Err(RuntimeError::OutOfMemoryBounds);
+            }
+            if !s.add_thread() {
+                return Err(RuntimeError::TooManyThreads);
             }
+            slot = Some(s.get(i + 1) as usize);
+            s.set(i, 0);
+            s.set(i + 1, 1);
+            s.spawn(Thread {
+                pc: pc + 1,
+                pointer: i + 1,
+                calls: t.calls.clone(),
+            })
+        }
+        _ => unreachable!(),
+    }
+    Ok(slot)
+}
+
+/// Which instructions are in the body of a procedure, with one more for the end of the program.
+fn in_procedures(prog: &Program) -> Vec<bool> {
+    let mut inside = vec![false; prog.insts.len() + 1];
+    for (p, inst) in prog.insts.iter().enumerate() {
+        if let Instruction::ProcStart(end) = *inst {
+            inside[p + 1..=end].iter_mut().for_each(|i| *i = true)
+        }
+    }
+    inside
+}
+
+/// Whether a thread of a snapshot can run: its return addresses are calls, all in procedures but
+/// the first, and it's in a procedure exactly when it has a call to return from.
+fn valid(prog: &Program, inside: &[bool], pc: usize, pointer: usize, calls: &[usize]) -> bool {
+    pc <= prog.insts.len()
+        && pointer < MEM_SIZE
+        && calls.iter().enumerate().all(|(k, &ret)| {
+            matches!(prog.insts.get(ret), Some(Instruction::Call)) && inside[ret] == (k > 0)
+        })
+        && inside[pc] != calls.is_empty()
+}
+
+/// State changed by a single step: every instruction writes at most the cell under the pointer,
+/// and a procedure, a call or a thread.
+struct Undo {
+    pc: usize,
+    i: usize,
+    /// Value of the cell under the pointer
+    v: u8,
+    b: usize,
+    /// Length of the output
+    out: usize,
+    /// Procedure replaced by `(`, return address taken by `)` or cell overwritten by `Y`
+    slot: Option<usize>,
+    /// Index of the thread that ran the step
+    thread: usize,
+}
+
+/// A condition that can stop a running program, checked in between batches of instructions.
+pub trait Stop: Send {
+    /// Maximum number of instructions that can be run before the next check.
+    fn budget(&self, _machine: &Machine) -> u64 {
+        CHECK_INTERVAL
+    }
+
+    /// Returns an error if the program should be stopped.
+    fn check(&mut self, machine: &Machine) -> Result<(), Error>;
+}
+
+/// Stops the program once the wall-clock deadline has passed.
+pub struct Deadline(pub Instant);
+
+impl Stop for Deadline {
+    fn check(&mut self, _: &Machine) -> Result<(), Error> {
+        if Instant::now() >= self.0 {
+            Err(Error::Timeout)
+        } else {
+            Ok(())
         }
-        if let Ok(_) | Err(mpsc::TryRecvError::Disconnected) = rx.try_recv() {
-            return Err(Error::Timeout);
+    }
+}
+
+/// Stops the program before it executes more than the given number of instructions.
+pub struct StepLimit(pub u64);
+
+impl Stop for StepLimit {
+    fn budget(&self, machine: &Machine) -> u64 {
+        self.0.saturating_sub(machine.steps)
+    }
+
+    fn check(&mut self, machine: &Machine) -> Result<(), Error> {
+        if machine.steps >= self.0 {
+            Err(Error::StepLimitExceeded)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Stop for CancelToken {
+    fn check(&mut self, _: &Machine) -> Result<(), Error> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
         } else {
-            p += 1
+            Ok(())
+        }
+    }
+}
+
+/// Runs the machine until the program ends or one of the stop conditions is met.
+pub fn exec(machine: &mut Machine, stops: &mut [Box<dyn Stop>]) -> Result<(), Error> {
+    loop {
+        let budget = stops
+            .iter()
+            .map(|s| s.budget(machine))
+            .min()
+            .unwrap_or(u64::MAX);
+        if machine.run(budget)? {
+            return Ok(());
+        }
+        check(machine, stops)?;
+    }
+}
+
+/// Checks the stop conditions, saving a snapshot if one of them stops the program.
+fn check(machine: &mut Machine, stops: &mut [Box<dyn Stop>]) -> Result<(), Error> {
+    for s in stops.iter_mut() {
+        if let Err(e) = s.check(machine) {
+            if let Some(path) = &machine.checkpoint {
+                machine.snapshot().save(path)?;
+            }
+            return Err(e);
         }
     }
-    Ok(output)
+    Ok(())
 }
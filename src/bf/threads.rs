@@ -0,0 +1,218 @@
+use crate::{
+    analysis::{check_input, safe_moves},
+    bf::{
+        self,
+        interpreter::{step, State, Thread, MEM_SIZE},
+        Program, MAX_THREADS,
+    },
+    CancelToken, Dump, DumpFn, Error, Exec, Position,
+};
+use std::{
+    panic,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How often the deadline and the cancel token are checked while the threads run.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// State shared by every thread of the program.
+struct Shared {
+    prog: Program,
+    source: String,
+    /// Moves that can't leave the tape, skipping the bounds check
+    safe: Vec<bool>,
+    mem: Vec<AtomicU8>,
+    /// Index of the `(` of every defined procedure by name, `usize::MAX` if there's none
+    procs: Vec<AtomicUsize>,
+    input: Vec<u8>,
+    /// Input cursor
+    cursor: AtomicUsize,
+    output: Mutex<String>,
+    on_debug: Mutex<Option<DumpFn>>,
+    steps: AtomicU64,
+    max_steps: Option<u64>,
+    /// Threads started so far, the first one included
+    started: AtomicUsize,
+    /// Threads that haven't finished yet
+    running: AtomicUsize,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    /// Tells every thread to stop, after an error or when the program is stopped
+    stop: AtomicBool,
+    /// First error of the program
+    error: Mutex<Option<Error>>,
+}
+
+impl Shared {
+    /// Stops the program, keeping the error if it's the first one.
+    fn fail(&self, e: Error) {
+        self.error.lock().unwrap().get_or_insert(e);
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    fn position(&self, pc: usize) -> Position {
+        Position::new(&self.source, self.prog.spans[pc].start)
+    }
+}
+
+/// Interprets the program with every thread on its own OS thread.
+pub fn run(mut cfg: Exec) -> Result<String, Error> {
+    if cfg.resume.is_some() {
+        return Err(Error::Unsupported("resuming a snapshot with real threads"));
+    }
+    if cfg.trace.is_some() {
+        return Err(Error::Unsupported("tracing real threads"));
+    }
+    if cfg.checkpoint.is_some() {
+        return Err(Error::Unsupported("checkpoints of real threads"));
+    }
+    let prog = bf::parse(&cfg)?;
+    let input = cfg.input.take().map(String::into_bytes).unwrap_or_default();
+    check_input(&prog, &cfg.program, input.len())?;
+    let shared = Arc::new(Shared {
+        safe: safe_moves(&prog),
+        prog,
+        source: cfg.program.clone(),
+        mem: (0..MEM_SIZE).map(|_| AtomicU8::new(0)).collect(),
+        procs: (0..256).map(|_| AtomicUsize::new(usize::MAX)).collect(),
+        input,
+        cursor: AtomicUsize::new(0),
+        output: Mutex::new(String::new()),
+        on_debug: Mutex::new(cfg.on_debug.take()),
+        steps: AtomicU64::new(0),
+        max_steps: cfg.steps,
+        started: AtomicUsize::new(1),
+        running: AtomicUsize::new(0),
+        handles: Mutex::new(Vec::new()),
+        stop: AtomicBool::new(false),
+        error: Mutex::new(None),
+    });
+    start(&shared, Thread::default());
+
+    let deadline = cfg.time.map(|t| Instant::now() + t);
+    while shared.running.load(Ordering::SeqCst) > 0 {
+        if cfg.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            shared.fail(Error::Cancelled)
+        } else if deadline.is_some_and(|d| Instant::now() >= d) {
+            shared.fail(Error::Timeout)
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let handles = std::mem::take(&mut *shared.handles.lock().unwrap());
+    for handle in handles {
+        if let Err(e) = handle.join() {
+            panic::resume_unwind(e)
+        }
+    }
+    let error = shared.error.lock().unwrap().take();
+    match error {
+        Some(e) => Err(e),
+        None => Ok(std::mem::take(&mut *shared.output.lock().unwrap())),
+    }
+}
+
+/// Starts a thread with the given registers.
+fn start(shared: &Arc<Shared>, thread: Thread) {
+    shared.running.fetch_add(1, Ordering::SeqCst);
+    let s = Running(shared.clone());
+    let handle = thread::spawn(move || {
+        if let Err(e) = exec(&s.0, thread) {
+            s.0.fail(e)
+        }
+    });
+    shared.handles.lock().unwrap().push(handle);
+}
+
+/// Counts a thread as running until it's dropped, even by a panic, so that `run` gets to join
+/// it and raise the panic again.
+struct Running(Arc<Shared>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.stop.store(true, Ordering::SeqCst);
+        }
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Runs a thread until it finishes, fails or the program is stopped.
+fn exec(shared: &Arc<Shared>, mut t: Thread) -> Result<(), Error> {
+    let mut state = View(shared);
+    while t.pc < shared.prog.insts.len() && !shared.stop.load(Ordering::Relaxed) {
+        if let Some(max) = shared.max_steps {
+            if shared.steps.fetch_add(1, Ordering::Relaxed) >= max {
+                return Err(Error::StepLimitExceeded);
+            }
+        }
+        if let Err(e) = step(&shared.prog, &shared.safe, &mut t, &mut state) {
+            return Err(Error::Runtime(e(shared.position(t.pc))));
+        }
+    }
+    Ok(())
+}
+
+/// The state shared by the threads, as seen by one of them.
+///
+/// Every instruction loads the cell and stores it back: what other threads write in between is
+/// lost, like in any program without synchronization.
+struct View<'a>(&'a Arc<Shared>);
+
+impl State for View<'_> {
+    fn get(&self, i: usize) -> u8 {
+        self.0.mem[i].load(Ordering::Relaxed)
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        self.0.mem[i].store(v, Ordering::Relaxed)
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let b = self.0.cursor.fetch_add(1, Ordering::Relaxed);
+        self.0.input.get(b).copied()
+    }
+
+    fn print(&mut self, v: u8) {
+        self.0.output.lock().unwrap().push(v as char)
+    }
+
+    fn dump(&mut self, offset: usize, pointer: usize) {
+        let mem: Vec<u8> = self
+            .0
+            .mem
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .collect();
+        let dump = Dump::new(offset, pointer, &mem);
+        match &mut *self.0.on_debug.lock().unwrap() {
+            Some(f) => f(&dump),
+            None => eprintln!("{}", dump),
+        }
+    }
+
+    fn procedure(&self, name: u8) -> Option<usize> {
+        match self.0.procs[name as usize].load(Ordering::Relaxed) {
+            usize::MAX => None,
+            start => Some(start),
+        }
+    }
+
+    fn define(&mut self, name: u8, start: usize) -> Option<usize> {
+        match self.0.procs[name as usize].swap(start, Ordering::Relaxed) {
+            usize::MAX => None,
+            start => Some(start),
+        }
+    }
+
+    fn add_thread(&mut self) -> bool {
+        self.0.started.fetch_add(1, Ordering::SeqCst) < MAX_THREADS
+    }
+
+    fn spawn(&mut self, thread: Thread) {
+        start(self.0, thread)
+    }
+}
@@ -1,137 +1,487 @@
-use crate::{bf::*, check_brackets, Error, RuntimeError};
+use crate::{
+    analysis::{check_input, safe_moves},
+    bf::*,
+    CancelToken, Dump, Error, Position, RuntimeError,
+};
 use rand::{distributions::Alphanumeric, Rng};
-use std::{env, fs, path::PathBuf};
-use subprocess::{Exec, ExitStatus, Redirection};
-
-pub fn run(
-    program: &str,
-    input: Option<String>,
-    time: Option<std::time::Duration>,
-    tmp_path: Option<PathBuf>,
-) -> Result<String, Error> {
-    let code = translate(program, input)?;
-    let name = "bf".to_owned()
-        + &rand::thread_rng()
-            .sample_iter(Alphanumeric)
-            .take(14)
-            .collect::<String>();
-    let basepath = if let Some(s) = tmp_path {
-        s
-    } else {
-        env::current_dir().unwrap()
-    };
-    {
-        let mut source = basepath.clone();
-        source.push(name.clone() + ".rs");
-        fs::write(source, code).unwrap();
+use std::{
+    env, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use subprocess::{Exec, ExitStatus, Popen, Redirection};
+
+/// How often rustc and the executable are checked for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long to wait for what a stopped process printed, or for rustc to let go of its files.
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+pub fn run(mut cfg: crate::Exec) -> Result<String, Error> {
+    if cfg.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+        return Err(Error::Cancelled);
     }
+    let (dir, source, exe) = write_source(&cfg)?;
+    let result = match build(&dir, &source, cfg.cancel.as_ref()) {
+        Ok(()) => {
+            let deadline = cfg.time.map(|t| Instant::now() + t);
+            let p = Exec::cmd(exe)
+                .stdout(Redirection::Pipe)
+                .stderr(Redirection::Pipe)
+                .popen()
+                .unwrap();
+            match wait(p, deadline, cfg.cancel.as_ref()) {
+                Ok((code, out, err)) => finish(code, out, &err, &mut cfg),
+                Err((e, err)) => {
+                    // the dumps are most useful when the program doesn't end
+                    forward_dumps(&err, &mut cfg);
+                    Err(e)
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
+    cleanup(&dir);
+    result
+}
+
+/// Compiles the source into `dir`, stopping rustc once `cancel` is cancelled.
+fn build(dir: &Path, source: &Path, cancel: Option<&CancelToken>) -> Result<(), Error> {
     let rustc = Exec::cmd("rustc")
         .arg("-Copt-level=3")
-        .arg(name.clone() + ".rs")
+        .arg("--out-dir")
+        .arg(dir)
+        .arg(source)
+        .stdout(Redirection::Pipe)
         .stderr(Redirection::Pipe)
-        .capture()
-        .unwrap();
-    if !rustc.success() {
-        return Err(Error::Compile(rustc.stderr_str()));
-    }
-    let mut exe = basepath;
-    if cfg!(windows) {
-        exe.push(format!(r".\{}.exe", name))
-    } else {
-        exe.push(format!("./{}", name))
-    };
+        .popen()
+        .map_err(Error::Subprocess)?;
+    match wait(rustc, None, cancel) {
+        Ok((Some(0), _, _)) => Ok(()),
+        Ok((_, _, err)) => Err(Error::Compile(String::from_utf8_lossy(&err).into_owned())),
+        Err((e, _)) => Err(e),
+    }
+}
 
-    let result = if let Some(t) = time {
-        let mut p = Exec::cmd(exe)
-            .stdout(Redirection::Pipe)
-            .stderr(Redirection::Pipe)
-            .popen()
-            .unwrap();
-        match p.wait_timeout(t) {
-            Ok(Some(ExitStatus::Exited(c))) => match c {
-                0 => Ok(p
-                    .communicate_start(None)
-                    .read()
-                    .unwrap()
-                    .0
-                    .unwrap_or(vec![])
-                    .into_iter()
-                    .map(|c| c as char)
-                    .collect::<String>()
-                    .trim()
-                    .to_owned()),
-                10 => Err(Error::Runtime(RuntimeError::InputTooShort)),
-                _ => Err(Error::Runtime(RuntimeError::OutOfMemoryBounds)),
-            },
-            Ok(Some(_)) => Err(Error::Runtime(RuntimeError::Signal)),
-            Ok(None) => {
-                p.terminate().unwrap();
-                Err(Error::Timeout)
-            }
-            Err(e) => Err(Error::Subprocess(e)),
+/// Exit code of a process with what it printed on stdout and stderr, or the error that stopped
+/// it with what it printed on stderr until then.
+type Waited = Result<(Option<u32>, Vec<u8>, Vec<u8>), (Error, Vec<u8>)>;
+
+/// Waits for the process to exit, terminating it once `cancel` is cancelled or the deadline has
+/// passed.
+fn wait(mut p: Popen, deadline: Option<Instant>, cancel: Option<&CancelToken>) -> Waited {
+    // read while waiting so the process can't block on a full pipe
+    let mut comm = p.communicate_start(None);
+    let (mut out, mut err) = (Vec::new(), Vec::new());
+    loop {
+        let mut wait = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        if cancel.is_some() {
+            wait = Some(wait.map_or(POLL_INTERVAL, |w| w.min(POLL_INTERVAL)))
         }
-    } else {
-        let p = Exec::cmd(exe)
-            .stdout(Redirection::Pipe)
-            .stderr(Redirection::Pipe)
-            .capture()
-            .unwrap();
-        if let ExitStatus::Exited(c) = p.exit_status {
-            match c {
-                0 => Ok(p.stdout_str().trim().to_owned()),
-                10 => Err(Error::Runtime(RuntimeError::InputTooShort)),
-                _ => Err(Error::Runtime(RuntimeError::OutOfMemoryBounds)),
-            }
+        if let Some(wait) = wait {
+            comm = comm.limit_time(wait);
+        }
+        let (closed, (o, e)) = match comm.read() {
+            Ok(capture) => (true, capture),
+            Err(e) if e.kind() == ErrorKind::TimedOut => (false, e.capture),
+            Err(e) => return Err((io_error(e.error), err)),
+        };
+        out.extend(o.unwrap_or_default());
+        err.extend(e.unwrap_or_default());
+        if closed {
+            return match p.wait() {
+                Ok(status) => Ok((exit_code(status), out, err)),
+                Err(e) => Err((Error::Subprocess(e), err)),
+            };
+        }
+        let stopped = if cancel.is_some_and(CancelToken::is_cancelled) {
+            Error::Cancelled
+        } else if deadline.is_some_and(|d| Instant::now() >= d) {
+            Error::Timeout
         } else {
-            Err(Error::Runtime(RuntimeError::Signal))
+            continue;
+        };
+        p.terminate().unwrap();
+        p.wait().ok();
+        let (_, e) = match comm.limit_time(STOP_GRACE).read() {
+            Ok(capture) => capture,
+            Err(e) => e.capture,
+        };
+        err.extend(e.unwrap_or_default());
+        return Err((stopped, err));
+    }
+}
+
+#[cfg(feature = "async")]
+pub async fn run_async(mut cfg: crate::Exec) -> Result<String, Error> {
+    if cfg.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+        return Err(Error::Cancelled);
+    }
+    let (dir, source, exe) = write_source(&cfg)?;
+    let result = match build_async(&dir, &source, cfg.cancel.as_ref()).await {
+        Ok(()) => {
+            let deadline = cfg.time.map(|t| Instant::now() + t);
+            let p = tokio::process::Command::new(exe)
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(io_error);
+            match p {
+                Ok(p) => match wait_async(p, deadline, cfg.cancel.as_ref()).await {
+                    Ok((code, out, err)) => finish(code, out, &err, &mut cfg),
+                    Err((e, err)) => {
+                        // the dumps are most useful when the program doesn't end
+                        forward_dumps(&err, &mut cfg);
+                        Err(e)
+                    }
+                },
+                Err(e) => Err(e),
+            }
         }
+        Err(e) => Err(e),
     };
-    cleanup(&name);
+    cleanup(&dir);
     result
 }
 
-pub fn translate(program: &str, input: Option<String>) -> Result<String, Error> {
-    check_brackets(program)?;
-    let i1 = program.to_inst()?;
-    Ok(to_rust(i1, input))
+/// Async version of [`build`].
+#[cfg(feature = "async")]
+async fn build_async(dir: &Path, source: &Path, cancel: Option<&CancelToken>) -> Result<(), Error> {
+    let rustc = tokio::process::Command::new("rustc")
+        .arg("-Copt-level=3")
+        .arg("--out-dir")
+        .arg(dir)
+        .arg(source)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(io_error)?;
+    match wait_async(rustc, None, cancel).await {
+        Ok((Some(0), _, _)) => Ok(()),
+        Ok((_, _, err)) => Err(Error::Compile(String::from_utf8_lossy(&err).into_owned())),
+        Err((e, _)) => Err(e),
+    }
+}
+
+/// Async version of [`wait`].
+#[cfg(feature = "async")]
+async fn wait_async(
+    mut p: tokio::process::Child,
+    deadline: Option<Instant>,
+    cancel: Option<&CancelToken>,
+) -> Waited {
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt},
+        task::JoinHandle,
+        time,
+    };
+
+    // read while waiting so the process can't block on a full pipe
+    fn read_all<R>(mut r: R) -> JoinHandle<std::io::Result<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            r.read_to_end(&mut buf).await.map(|_| buf)
+        })
+    }
+
+    let stdout = read_all(p.stdout.take().unwrap());
+    let stderr = read_all(p.stderr.take().unwrap());
+    loop {
+        let mut wait = deadline.map_or(POLL_INTERVAL, |d| {
+            d.saturating_duration_since(Instant::now())
+        });
+        if cancel.is_some() {
+            wait = wait.min(POLL_INTERVAL)
+        }
+        match time::timeout(wait, p.wait()).await {
+            Ok(Ok(status)) => {
+                let code = status.code().map(|c| c as u32);
+                return match (stdout.await, stderr.await) {
+                    (Ok(Ok(out)), Ok(Ok(err))) => Ok((code, out, err)),
+                    (Ok(Err(e)), _) | (_, Ok(Err(e))) => Err((io_error(e), Vec::new())),
+                    _ => Err((Error::Runtime(RuntimeError::Signal), Vec::new())),
+                };
+            }
+            Ok(Err(e)) => return Err((io_error(e), Vec::new())),
+            Err(_) => {
+                let stopped = if cancel.is_some_and(CancelToken::is_cancelled) {
+                    Error::Cancelled
+                } else if deadline.is_some_and(|d| Instant::now() >= d) {
+                    Error::Timeout
+                } else {
+                    continue;
+                };
+                p.kill().await.ok();
+                let err = match time::timeout(STOP_GRACE, stderr).await {
+                    Ok(Ok(Ok(err))) => err,
+                    _ => Vec::new(),
+                };
+                return Err((stopped, err));
+            }
+        }
+    }
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::Subprocess(subprocess::PopenError::IoError(e))
 }
 
-fn cleanup(name: &str) {
-    fs::remove_file(format!("{}.rs", name)).unwrap();
-    fs::remove_file(if cfg!(windows) {
-        format!(".\\{}.exe", name)
+/// Writes the translated program in a new directory with a random name, where rustc also puts
+/// the executable and its temporary files. Returns the directory, the source and the executable.
+fn write_source(cfg: &crate::Exec) -> Result<(PathBuf, PathBuf, PathBuf), Error> {
+    let code = translate(cfg)?;
+    // the executable creates it again, failing here gives the same error as the interpreter
+    if let Some(path) = &cfg.trace {
+        fs::File::create(path).map_err(Error::Io)?;
+    }
+    let name = "bf".to_owned()
+        + &rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(14)
+            .collect::<String>();
+    let mut dir = if let Some(s) = &cfg.tmp_path {
+        s.clone()
     } else {
-        format!("./{}", name)
-    })
-    .unwrap();
+        env::current_dir().unwrap()
+    };
+    dir.push(&name);
+    fs::create_dir(&dir).map_err(Error::Io)?;
+    let source = dir.join(name.clone() + ".rs");
+    fs::write(&source, code).unwrap();
+    let exe = dir.join(if cfg!(windows) { name + ".exe" } else { name });
+    Ok((dir, source, exe))
+}
+
+/// Passes the `#` dumps printed by the executable to the callback, or prints them.
+fn forward_dumps(stderr: &[u8], cfg: &mut crate::Exec) {
+    if !cfg.ext.debug {
+        return;
+    }
+    for dump in String::from_utf8_lossy(stderr)
+        .lines()
+        .filter_map(Dump::parse)
+    {
+        match &mut cfg.on_debug {
+            Some(f) => f(&dump),
+            None => eprintln!("{}", dump),
+        }
+    }
+}
+
+fn exit_code(status: ExitStatus) -> Option<u32> {
+    match status {
+        ExitStatus::Exited(c) => Some(c),
+        _ => None,
+    }
+}
+
+/// Builds the result of the program from what the executable printed and its exit code, `None`
+/// means it was stopped by a signal.
+fn finish(
+    code: Option<u32>,
+    stdout: Vec<u8>,
+    stderr: &[u8],
+    cfg: &mut crate::Exec,
+) -> Result<String, Error> {
+    forward_dumps(stderr, cfg);
+    // offset of the failing instruction, printed by `fail!`
+    let pos = String::from_utf8_lossy(stderr)
+        .lines()
+        .rev()
+        .find_map(|l| l.strip_prefix('@')?.parse().ok())
+        .unwrap_or(0);
+    let pos = Position::new(&cfg.program, pos);
+    match code {
+        Some(0) => Ok(stdout
+            .into_iter()
+            .map(|c| c as char)
+            .collect::<String>()
+            .trim()
+            .to_owned()),
+        Some(10) => Err(Error::Runtime(RuntimeError::InputTooShort(pos))),
+        Some(11) => Err(Error::StepLimitExceeded),
+        Some(12) => Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(pos))),
+        Some(13) => Err(Error::Runtime(RuntimeError::UndefinedProcedure(pos))),
+        Some(14) => Err(Error::Runtime(RuntimeError::StackOverflow(pos))),
+        // a panic, like a failed write to the trace
+        Some(c) => Err(Error::Io(io::Error::other(format!(
+            "the executable exited with code {}: {}",
+            c,
+            String::from_utf8_lossy(stderr).trim()
+        )))),
+        None => Err(Error::Runtime(RuntimeError::Signal)),
+    }
 }
 
-fn to_rust(inst: Vec<Instruction>, input: Option<String>) -> String {
+pub fn translate(cfg: &crate::Exec) -> Result<String, Error> {
+    if cfg.resume.is_some() {
+        return Err(Error::Unsupported(
+            "resuming a snapshot with the transpiler",
+        ));
+    }
+    if cfg.ext.fork {
+        return Err(Error::Unsupported("forking with the transpiler"));
+    }
+    if cfg.checkpoint.is_some() {
+        return Err(Error::Unsupported("checkpoints with the transpiler"));
+    }
+    let i1 = parse(cfg)?;
+    let input = cfg.input.as_ref().map_or(0, String::len);
+    check_input(&i1, &cfg.program, input)?;
+    Ok(to_rust(i1, cfg))
+}
+
+/// Removes the directory made by [`write_source`].
+fn cleanup(dir: &Path) {
+    // a linker left running by a stopped rustc can still be writing to it, once the directory is
+    // gone it can't write anymore
+    let deadline = Instant::now() + STOP_GRACE;
+    while fs::remove_dir_all(dir).is_err() && dir.exists() && Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL)
+    }
+}
+
+fn to_rust(prog: Program, cfg: &crate::Exec) -> String {
     const START: &str = "use std::num::Wrapping;
 fn main() {
 let mut _m = [Wrapping(0u8); 30000];
 let (mut _p, mut _b) = (0usize, 0usize);
 let mut _o = String::new();\n";
     const END: &str = "println!(\"{}\", _o);}";
-    let mut code = if let Some(s) = input {
-        format!("let _i = \"{}\";\n", s)
+    // prints the same text as `Dump`'s Display impl
+    const DUMP: &str = "fn _d(pos: usize, m: &[std::num::Wrapping<u8>], p: usize) {
+let (a, b) = (p.saturating_sub(RADIUS), (p + RADIUS + 1).min(m.len()));
+let c: Vec<String> = m[a..b].iter().map(|c| c.0.to_string()).collect();
+eprintln!(\"#{}: pointer {}, cells {}..{}: {}\", pos, p, a, b, c.join(\" \"));
+}\n";
+    let steps = cfg.steps;
+    // declared even without input, for reads that fail
+    let mut code = format!("let _i = {:?};\n", cfg.input.as_deref().unwrap_or(""));
+    // the trace is buffered, it has to be flushed before exiting
+    if let Some(path) = &cfg.trace {
+        code.push_str(&format!(
+            "use std::io::Write;
+let mut _t = std::io::BufWriter::new(std::fs::File::create({:?}).unwrap());
+macro_rules! trace {{ ($s:expr, $e:expr, $b:expr) => {{
+writeln!(_t, \"{{}} {{}} {{}} {{}} {{}} {{}}\", $s, $e, $b.0, $b.1, _p, _m[_p].0).unwrap(); }} }}
+macro_rules! exit {{ ($c:expr) => {{ {{ _t.flush().unwrap(); std::process::exit($c) }} }} }}\n",
+            path.display().to_string()
+        ));
     } else {
-        String::new()
-    };
-    for i in inst {
+        code.push_str("macro_rules! exit { ($c:expr) => { std::process::exit($c) } }\n");
+    }
+    // reports the offset of the failing instruction to `finish`
+    code.push_str(
+        "macro_rules! fail {
+($c:expr, $pos:expr) => { { eprintln!(\"@{}\", $pos); exit!($c) } } }\n",
+    );
+    // every instruction is counted before running, like the interpreter does
+    if let Some(n) = steps {
+        code.push_str(&format!(
+            "let mut _s = 0u64;
+macro_rules! step {{ () => {{ if _s == {} {{ exit!(11) }} _s += 1; }} }}\n",
+            n
+        ));
+    }
+    // procedures can be called from anywhere: the program becomes a loop running one
+    // instruction at a time, where `_c` is the index of the next one
+    let dispatch = cfg.ext.procedures;
+    if dispatch {
+        code.push_str(&format!(
+            "let mut _f = [usize::MAX; 256];
+let mut _r: Vec<usize> = Vec::new();
+let mut _c = 0usize;
+while _c < {} {{
+match _c {{\n",
+            prog.insts.len()
+        ));
+    }
+    // moves proven to stay on the tape don't need a check
+    let safe = safe_moves(&prog);
+    for (pc, ((i, span), safe)) in prog.insts.into_iter().zip(prog.spans).zip(safe).enumerate() {
+        if dispatch {
+            code.push_str(&format!("{} => {{\n", pc));
+        }
+        if steps.is_some() {
+            code.push_str("step!();\n");
+        }
+        let trace = format!("trace!({}, {}, _q);\n", span.start, span.end);
+        // loops and procedures record the check before the jump
+        let jumps = matches!(
+            i,
+            Instruction::LoopStart(_)
+                | Instruction::LoopEnd(_)
+                | Instruction::ProcStart(_)
+                | Instruction::ProcEnd
+                | Instruction::Call
+        );
+        if cfg.trace.is_some() {
+            code.push_str("let _q = (_p, _m[_p].0);\n");
+            if jumps {
+                code.push_str(&trace);
+            }
+        }
         match i {
-            Instruction::Right(x) => code.push_str(&format!("_p += {};\n", x)),
-            Instruction::Left(x) => code.push_str(&format!("_p -= {};\n", x)),
+            Instruction::Right(x) if safe => code.push_str(&format!("_p += {};\n", x)),
+            Instruction::Left(x) if safe => code.push_str(&format!("_p -= {};\n", x)),
+            Instruction::Right(x) => code.push_str(&format!(
+                "if _p + {} >= 30000 {{ fail!(12, {}) }} _p += {};\n",
+                x, span.start, x
+            )),
+            Instruction::Left(x) => code.push_str(&format!(
+                "if _p < {} {{ fail!(12, {}) }} _p -= {};\n",
+                x, span.start, x
+            )),
             Instruction::Add(x) => code.push_str(&format!("_m[_p] += Wrapping({});\n", x)),
             Instruction::Sub(x) => code.push_str(&format!("_m[_p] -= Wrapping({});\n", x)),
             Instruction::Print => code.push_str("_o.push(_m[_p].0 as char)\n;"),
-            Instruction::Read => code.push_str(
-                "_m[_p] = { _b += 1; if let Some(c) = _i.as_bytes().get(_b-1) { Wrapping(*c)
-} else { std::process::exit(10) }};\n",
-            ),
-            Instruction::LoopStart => code.push_str("while _m[_p].0 != 0 {\n"),
-            Instruction::LoopEnd => code.push_str("}\n"),
+            Instruction::Read => code.push_str(&format!(
+                "_m[_p] = {{ _b += 1; if let Some(c) = _i.as_bytes().get(_b-1) {{ Wrapping(*c)
+}} else {{ fail!(10, {}) }}}};\n",
+                span.start
+            )),
+            Instruction::LoopStart(end) if dispatch => {
+                code.push_str(&format!("if _m[_p].0 == 0 {{ _c = {} }}\n", end))
+            }
+            Instruction::LoopEnd(start) if dispatch => {
+                code.push_str(&format!("if _m[_p].0 != 0 {{ _c = {} }}\n", start))
+            }
+            Instruction::LoopStart(_) => code.push_str("while _m[_p].0 != 0 {\n"),
+            Instruction::LoopEnd(_) => code.push_str("}\n"),
+            Instruction::Debug => code.push_str(&format!("_d({}, &_m, _p);\n", span.start)),
+            Instruction::ProcStart(end) => {
+                code.push_str(&format!("_f[_m[_p].0 as usize] = _c; _c = {};\n", end))
+            }
+            Instruction::ProcEnd => code.push_str("_c = _r.pop().unwrap();\n"),
+            Instruction::Call => code.push_str(&format!(
+                "match _f[_m[_p].0 as usize] {{
+usize::MAX => fail!(13, {0}),
+_ if _r.len() == {1} => fail!(14, {0}),
+f => {{ _r.push(_c); _c = f }} }}\n",
+                span.start, MAX_CALLS
+            )),
+            // refused by `translate`
+            Instruction::Fork => unreachable!(),
         }
+        if cfg.trace.is_some() && !jumps {
+            code.push_str(&trace)
+        }
+        if dispatch {
+            code.push_str("}\n");
+        }
+    }
+    if dispatch {
+        code.push_str("_ => unreachable!(),\n}\n_c += 1;\n}\n");
+    }
+    if cfg.ext.debug {
+        let dump = DUMP.replace("RADIUS", &DUMP_RADIUS.to_string());
+        format!("{}{}{}{}", dump, START, code, END)
+    } else {
+        format!("{}{}{}", START, code, END)
     }
-    format!("{}{}{}", START, code, END)
 }
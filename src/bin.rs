@@ -1,29 +1,88 @@
-use std::{env, fs, io, process};
+use std::{env, fs, io::{self, Read}, process};
 use bf_lib::*;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("fmt") => return format(&args),
+        Some("gen") => return generate(&args),
+        Some("asm") => return assemble(&args),
+        Some("ook") => return to_ook(&args),
+        _ => (),
+    }
     let mut prog = String::new();
+    let mut file = None;
+    let mut debug = false;
+    let mut profile = false;
+    let mut procedures = false;
+    let mut fork = false;
+    let mut real_threads = false;
+    let mut dialect = Dialect::Brainfuck;
 
-    if args.len() > 1 {
-        if args[1] == "-h" || args[1] == "--help" {
-            println!(
-                "Usage: {} <filename>
+    let mut opts = args[1..].iter();
+    while let Some(arg) = opts.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!(
+                    "Usage: {0} [options] <filename>
+       {0} fmt [options] <filename>, to reformat the program
+       {0} gen [text], to write a program printing the text
+       {0} asm <filename>, to compile a program written for bf_lib::asm
+       {0} ook <filename>, to translate a program to Ook!
 If a filename is not provided code is read from standard input
--h, --help: Shows this message",
-                args[0]
-            );
-        } else {
-            prog = fs::read_to_string(&args[1])
-                .expect("Error. I didn't quite get that.\nNo such file");
+-h, --help: Shows this message
+-d, --debug: Enables the # instruction, which dumps the tape around the pointer
+-P, --procedures: Enables pbrain's procedures, defined with ( ) and called with :
+-f, --fork: Enables the Y instruction, which forks the running thread
+-t, --threads: Runs every thread forked by Y on its own OS thread
+-p, --profile: Interprets the program, then prints how many times each part of it ran
+-o, --ook: Reads the program as Ook!
+-D, --dialect <file>: Reads the program with the command tokens listed in the file",
+                    args[0]
+                );
+                return;
+            }
+            "-d" | "--debug" => debug = true,
+            "-p" | "--profile" => profile = true,
+            "-P" | "--procedures" => procedures = true,
+            "-f" | "--fork" => fork = true,
+            "-t" | "--threads" => real_threads = true,
+            "-o" | "--ook" => dialect = Dialect::Ook,
+            "-D" | "--dialect" => {
+                let path = opts.next().expect("Error. I didn't quite get that.\nNo dialect file");
+                let config = fs::read_to_string(path)
+                    .expect("Error. I didn't quite get that.\nNo such dialect file");
+                match Substitution::parse(&config) {
+                    Ok(sub)     => dialect = Dialect::Custom(sub),
+                    Err(err)    => { eprint!("{}", err.render(&config)); process::exit(1) }
+                }
+            }
+            _ => file = Some(arg),
         }
+    }
+
+    if let Some(file) = file {
+        prog = fs::read_to_string(file)
+            .expect("Error. I didn't quite get that.\nNo such file");
     } else {
         io::stdin()
             .read_line(&mut prog)
             .expect("Error. I didn't quite get that");
     }
 
-    let input = if wants_input(&prog) {
+    let bf = match dialect.to_brainfuck(&prog) {
+        Ok(bf)      => bf,
+        Err(err)    => { eprint!("{}", err.render(&prog)); process::exit(1) }
+    };
+    // the analysis doesn't know about procedures and threads
+    if dialect == Dialect::Brainfuck && !procedures && !fork {
+        if let Ok(MemoryBounds { underflow: Some(pos), .. }) = memory_bounds(&prog) {
+            eprintln!("warning: the pointer can move left of cell 0 at {}", pos);
+        }
+    }
+
+    let reads = if procedures || fork { bf.contains(',') } else { wants_input(&bf) };
+    let input = if reads {
         let mut input = String::new();
         eprintln!("Enter the input characters");
         io::stdin()
@@ -32,8 +91,103 @@ If a filename is not provided code is read from standard input
         Some(input.trim().to_owned())
     } else { None };
 
-    match Exec::prog(&prog).input(input).run() {
+    let exec = Exec::prog(&prog)
+        .input(input)
+        .debug(debug)
+        .procedures(procedures)
+        .fork(fork)
+        .real_threads(real_threads)
+        .dialect(dialect);
+    if profile {
+        match exec.profile() {
+            Ok(p)       => { print!("{}", p.output); eprint!("\n{}", p) }
+            Err(err)    => { eprint!("{}", err.render(&prog)); process::exit(1) }
+        }
+        return;
+    }
+    match exec.run() {
         Ok(output)  => print!("{}", output),
-        Err(err)    => { eprintln!("{}", err); process::exit(1) }
+        Err(err)    => { eprint!("{}", err.render(&prog)); process::exit(1) }
+    }
+}
+
+fn format(args: &[String]) {
+    let mut style = Style::default();
+    let mut file = None;
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!(
+                    "Usage: {} fmt [options] <filename>
+If a filename is not provided code is read from standard input
+-h, --help: Shows this message
+-s, --strip-comments: Removes the comments
+-P, --procedures: Formats pbrain's ( ) and : as commands
+-f, --fork: Formats Y as a command",
+                    args[0]
+                );
+                return;
+            }
+            "-s" | "--strip-comments" => style.comments = false,
+            "-P" | "--procedures" => style.procedures = true,
+            "-f" | "--fork" => style.fork = true,
+            _ => file = Some(arg),
+        }
+    }
+
+    let mut prog = String::new();
+    if let Some(file) = file {
+        prog = fs::read_to_string(file)
+            .expect("Error. I didn't quite get that.\nNo such file");
+    } else {
+        io::stdin()
+            .read_to_string(&mut prog)
+            .expect("Error. I didn't quite get that");
+    }
+    match fmt(&prog, &style) {
+        Ok(pretty)  => print!("{}", pretty),
+        Err(err)    => { eprint!("{}", err.render(&prog)); process::exit(1) }
+    }
+}
+
+fn generate(args: &[String]) {
+    let text = if args.len() > 2 {
+        args[2..].join(" ").into_bytes()
+    } else {
+        let mut text = Vec::new();
+        io::stdin()
+            .read_to_end(&mut text)
+            .expect("Error. I didn't quite get that");
+        text
+    };
+    println!("{}", gen::print_string(&text));
+}
+
+fn assemble(args: &[String]) {
+    let mut prog = String::new();
+    if let Some(file) = args.get(2) {
+        prog = fs::read_to_string(file)
+            .expect("Error. I didn't quite get that.\nNo such file");
+    } else {
+        io::stdin()
+            .read_to_string(&mut prog)
+            .expect("Error. I didn't quite get that");
+    }
+    match asm::compile(&prog) {
+        Ok(bf)      => println!("{}", bf),
+        Err(err)    => { eprint!("{}", err.render(&prog)); process::exit(1) }
+    }
+}
+
+fn to_ook(args: &[String]) {
+    let mut prog = String::new();
+    if let Some(file) = args.get(2) {
+        prog = fs::read_to_string(file)
+            .expect("Error. I didn't quite get that.\nNo such file");
+    } else {
+        io::stdin()
+            .read_to_string(&mut prog)
+            .expect("Error. I didn't quite get that");
     }
+    print!("{}", Dialect::Ook.from_brainfuck(&prog));
 }
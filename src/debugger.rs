@@ -0,0 +1,284 @@
+use crate::{bf::interpreter::Machine, Error, Snapshot, Span};
+use std::collections::BTreeSet;
+
+/// Why the [`Debugger`] stopped.
+///
+/// [`Debugger`]: struct.Debugger.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A single instruction was executed.
+    Step,
+    /// The next instruction to run has a breakpoint, at the given span.
+    Breakpoint(Span),
+    /// The instruction at `span`, which just ran, triggered a watchpoint.
+    Watchpoint { watch: Watch, span: Span },
+    /// The program has ended.
+    Finished,
+}
+
+/// Condition on the tape that stops the [`Debugger`] as soon as it becomes true.
+///
+/// [`Debugger`]: struct.Debugger.html
+/// ```
+/// # use bf_lib::{Event, Exec, Watch};
+/// let mut dbg = Exec::prog("+++[>++<-]").debugger().unwrap();
+/// dbg.add_watch(Watch::Equals(1, 4));
+///
+/// match dbg.run_until_breakpoint().unwrap() {
+///     Event::Watchpoint { watch, span } => {
+///         assert_eq!(watch, Watch::Equals(1, 4));
+///         assert_eq!((span.start, span.end), (5, 7));
+///     }
+///     e => panic!("{:?}", e),
+/// }
+/// assert_eq!(dbg.memory()[0], 2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    /// The value of the cell at the index changes.
+    Changed(usize),
+    /// The cell at the index gets the value.
+    Equals(usize, u8),
+    /// The pointer moves to a cell after the index.
+    PointerPast(usize),
+}
+
+/// Runs a program with the interpreter one step at a time.
+///
+/// Created by [`Exec::debugger`], it gives access to the tape, pointers and input cursor in
+/// between instructions. Breakpoints are set on byte offsets of the source: they stop the program
+/// before running the instruction at that position (or the next one if the offset is a comment).
+///
+/// [`Exec::debugger`]: struct.Exec.html#method.debugger
+/// ```
+/// # use bf_lib::{Event, Exec};
+/// let mut dbg = Exec::prog("++[>+<-]>.").debugger().unwrap();
+/// dbg.add_breakpoint(5);
+///
+/// assert!(matches!(dbg.run_until_breakpoint(), Ok(Event::Breakpoint(_))));
+/// assert_eq!(&dbg.memory()[..2], &[2, 1]);
+/// dbg.run_until_breakpoint().unwrap();
+/// assert_eq!(&dbg.memory()[..2], &[1, 2]);
+/// ```
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<usize>,
+    /// Whether each instruction has a breakpoint
+    stop_at: Vec<bool>,
+    watches: Vec<Watch>,
+}
+
+impl Debugger {
+    pub(crate) fn new(machine: Machine) -> Debugger {
+        let stop_at = vec![false; machine.program().insts.len()];
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+            stop_at,
+            watches: Vec::new(),
+        }
+    }
+
+    /// Runs a single instruction.
+    ///
+    /// Returns [`Event::Watchpoint`] if it triggered one, [`Event::Finished`] if there's nothing
+    /// left to run after it.
+    ///
+    /// [`Event::Watchpoint`]: enum.Event.html#variant.Watchpoint
+    /// [`Event::Finished`]: enum.Event.html#variant.Finished
+    pub fn step(&mut self) -> Result<Event, Error> {
+        let span = self.machine.span();
+        let (pointer, cells) = (self.machine.pointer(), self.watched_cells());
+        let finished = self.machine.run(1)?;
+        let mem = self.machine.memory();
+        let hit = self.watches.iter().zip(cells).find(|(w, old)| match **w {
+            Watch::Changed(n) => mem[n] != *old,
+            Watch::Equals(n, v) => mem[n] == v && *old != v,
+            Watch::PointerPast(n) => pointer <= n && self.machine.stepped_pointer() > n,
+        });
+        match (hit, span) {
+            (Some((&watch, _)), Some(span)) => Ok(Event::Watchpoint { watch, span }),
+            _ if finished => Ok(Event::Finished),
+            _ => Ok(Event::Step),
+        }
+    }
+
+    /// Runs the program until the next instruction has a breakpoint, a watchpoint is triggered
+    /// or the program ends.
+    ///
+    /// At least one instruction is run, so calling this again continues from a breakpoint.
+    pub fn run_until_breakpoint(&mut self) -> Result<Event, Error> {
+        loop {
+            match self.step()? {
+                Event::Step if self.stop_at[self.machine.pc()] => {
+                    return Ok(Event::Breakpoint(self.machine.span().unwrap()))
+                }
+                Event::Step => (),
+                e => return Ok(e),
+            }
+        }
+    }
+
+    /// Adds a watchpoint, checked after every step.
+    ///
+    /// # Panics
+    ///
+    /// If the watched cell is out of the tape.
+    pub fn add_watch(&mut self, watch: Watch) {
+        if let Watch::Changed(n) | Watch::Equals(n, _) = watch {
+            assert!(n < self.machine.memory().len(), "watched cell out of bounds");
+        }
+        self.watches.push(watch)
+    }
+
+    /// Removes a watchpoint, returns whether there was one.
+    pub fn remove_watch(&mut self, watch: Watch) -> bool {
+        let len = self.watches.len();
+        self.watches.retain(|w| *w != watch);
+        self.watches.len() != len
+    }
+
+    /// The watchpoints, in the order they were added.
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /// Value of the cell checked by each watchpoint.
+    fn watched_cells(&self) -> Vec<u8> {
+        let mem = self.machine.memory();
+        self.watches
+            .iter()
+            .map(|w| match *w {
+                Watch::Changed(n) | Watch::Equals(n, _) => mem[n],
+                Watch::PointerPast(_) => 0,
+            })
+            .collect()
+    }
+
+    /// Starts or stops recording the changes made by each step, which allows going back with
+    /// [`step_back`] and [`rewind_to`]. Stopping drops the recorded history.
+    ///
+    /// Every step takes a few dozen bytes of memory while recording.
+    ///
+    /// [`step_back`]: struct.Debugger.html#method.step_back
+    /// [`rewind_to`]: struct.Debugger.html#method.rewind_to
+    pub fn record_history(&mut self, enabled: bool) {
+        self.machine.record_history(enabled)
+    }
+
+    /// Undoes the last step: tape, pointers, input cursor and output go back to how they were
+    /// before it.
+    ///
+    /// Returns false if there's no recorded step left to undo.
+    pub fn step_back(&mut self) -> bool {
+        self.machine.undo()
+    }
+
+    /// Brings the program to the state it had after `step` steps, going backwards through the
+    /// recorded history or running forward as needed.
+    ///
+    /// Going forward stops early if the program ends, or returns the error it encounters.
+    /// Returns an [`Error::Unsupported`] if the history doesn't go back far enough.
+    ///
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    /// ```
+    /// # use bf_lib::Exec;
+    /// let mut dbg = Exec::prog("+++[>++<-]>.").debugger().unwrap();
+    /// dbg.record_history(true);
+    /// dbg.rewind_to(12).unwrap();
+    /// assert_eq!(&dbg.memory()[..2], &[1, 4]);
+    ///
+    /// dbg.rewind_to(4).unwrap();
+    /// assert_eq!(&dbg.memory()[..2], &[3, 2]);
+    /// ```
+    pub fn rewind_to(&mut self, step: u64) -> Result<(), Error> {
+        let steps = self.machine.steps();
+        if step < steps {
+            if steps - step > self.machine.history_len() as u64 {
+                return Err(Error::Unsupported("history doesn't go back that far"));
+            }
+            while self.machine.steps() > step {
+                self.machine.undo();
+            }
+        } else {
+            self.machine.run(step - steps)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a breakpoint at the byte offset `pos` of the source.
+    pub fn add_breakpoint(&mut self, pos: usize) {
+        self.breakpoints.insert(pos);
+        self.update_breakpoints();
+    }
+
+    /// Removes the breakpoint at `pos`, returns whether there was one.
+    pub fn remove_breakpoint(&mut self, pos: usize) -> bool {
+        let removed = self.breakpoints.remove(&pos);
+        self.update_breakpoints();
+        removed
+    }
+
+    /// Byte offsets of the breakpoints, in ascending order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    fn update_breakpoints(&mut self) {
+        let spans = &self.machine.program().spans;
+        self.stop_at = vec![false; spans.len()];
+        for pos in &self.breakpoints {
+            let i = spans.partition_point(|s| s.end <= *pos);
+            if let Some(stop) = self.stop_at.get_mut(i) {
+                *stop = true
+            }
+        }
+    }
+
+    /// Source span of the next instruction, `None` once the program has finished.
+    pub fn span(&self) -> Option<Span> {
+        self.machine.span()
+    }
+
+    /// The whole tape.
+    pub fn memory(&self) -> &[u8] {
+        self.machine.memory()
+    }
+
+    /// Position of the tape pointer.
+    pub fn pointer(&self) -> usize {
+        self.machine.pointer()
+    }
+
+    /// Index of the next instruction to run.
+    pub fn instruction_pointer(&self) -> usize {
+        self.machine.pc()
+    }
+
+    /// Number of input bytes read so far.
+    pub fn input_cursor(&self) -> usize {
+        self.machine.cursor()
+    }
+
+    /// Output printed so far.
+    pub fn output(&self) -> &str {
+        self.machine.output()
+    }
+
+    /// Number of instructions run so far.
+    pub fn steps(&self) -> u64 {
+        self.machine.steps()
+    }
+
+    /// Saves the current state, it can be continued with [`Exec::resume`].
+    ///
+    /// [`Exec::resume`]: struct.Exec.html#method.resume
+    pub fn snapshot(&self) -> Snapshot {
+        self.machine.snapshot()
+    }
+
+    /// Checks if the program has ended.
+    pub fn finished(&self) -> bool {
+        self.machine.finished()
+    }
+}
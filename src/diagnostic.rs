@@ -0,0 +1,288 @@
+use crate::{
+    bf::{is_command, Extensions},
+    memory_bounds, Error, Lines, MemoryBounds, Position, RuntimeError, Span,
+};
+use std::fmt::Write;
+
+/// Kind of problem found by [`validate`].
+///
+/// [`validate`]: fn.validate.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A `[` without a matching `]`.
+    UnclosedBracket,
+    /// A `]` without a matching `[`.
+    UnopenedBracket,
+    /// `[]`, which never ends if the cell isn't zero.
+    InfiniteLoop,
+    /// Two instructions undoing each other, like `+-` or `<>`.
+    Cancellation,
+    /// A loop right after the end of another one: the cell is always zero, it never runs.
+    Unreachable,
+    /// A move that can take the pointer left of cell 0, see [`memory_bounds`].
+    ///
+    /// [`memory_bounds`]: fn.memory_bounds.html
+    Underflow,
+}
+
+impl Lint {
+    /// Whether the program can't run at all, the other lints are warnings.
+    pub fn is_error(self) -> bool {
+        matches!(self, Lint::UnclosedBracket | Lint::UnopenedBracket)
+    }
+}
+
+/// Problem found by [`validate`], with the part of the source it concerns.
+///
+/// [`validate`]: fn.validate.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub span: Span,
+    /// Position of the start of `span`.
+    pub position: Position,
+}
+
+/// Looks for every unmatched bracket and suspicious construct in the program, sorted by position.
+///
+/// Unlike [`check_brackets`] it doesn't stop at the first problem.
+///
+/// [`check_brackets`]: fn.check_brackets.html
+/// ```
+/// # use bf_lib::{validate, Lint};
+/// let lints: Vec<Lint> = validate("]+-[-][.]>[").into_iter().map(|d| d.lint).collect();
+/// assert_eq!(
+///     lints,
+///     [Lint::UnopenedBracket, Lint::Cancellation, Lint::Unreachable, Lint::UnclosedBracket]
+/// );
+/// ```
+pub fn validate(prog: &str) -> Vec<Diagnostic> {
+    let mut found = Vec::new();
+    let lines = Lines::new(prog);
+    let mut push = |lint, start, end| {
+        found.push(Diagnostic {
+            lint,
+            span: Span { start, end },
+            position: lines.position(prog, start),
+        })
+    };
+    // (offset, whether it's right after a loop) of every unclosed bracket
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    let mut unopened = false;
+    // previous instruction, `None` after a reported cancellation
+    let mut prev: Option<(usize, u8)> = None;
+    for (pos, b) in prog.bytes().enumerate() {
+        if !is_command(b, &Extensions::default()) {
+            continue;
+        }
+        let last = prev.map(|(_, b)| b);
+        match b {
+            b'[' => open.push((pos, last == Some(b']'))),
+            b']' => match open.pop() {
+                Some((start, dead)) => {
+                    if last == Some(b'[') {
+                        push(Lint::InfiniteLoop, start, pos + 1)
+                    }
+                    if dead {
+                        push(Lint::Unreachable, start, pos + 1)
+                    }
+                }
+                None => {
+                    unopened = true;
+                    push(Lint::UnopenedBracket, pos, pos + 1)
+                }
+            },
+            _ => (),
+        }
+        match (prev, b) {
+            (Some((start, b'+')), b'-')
+            | (Some((start, b'-')), b'+')
+            | (Some((start, b'<')), b'>')
+            | (Some((start, b'>')), b'<') => {
+                push(Lint::Cancellation, start, pos + 1);
+                prev = None
+            }
+            _ => prev = Some((pos, b)),
+        }
+    }
+    let unmatched = unopened || !open.is_empty();
+    for (start, _) in open {
+        push(Lint::UnclosedBracket, start, start + 1)
+    }
+    if !unmatched {
+        if let Ok(MemoryBounds {
+            underflow: Some(pos),
+            ..
+        }) = memory_bounds(prog)
+        {
+            let len = prog[pos.offset..].find(|c| c != '<' && c != '>');
+            push(Lint::Underflow, pos.offset, pos.offset + len.unwrap_or(1))
+        }
+    }
+    found.sort_by_key(|d| d.span.start);
+    found
+}
+
+/// Part of the source pointed at by a diagnostic.
+struct Label {
+    pos: Position,
+    text: &'static str,
+}
+
+impl Error {
+    /// Renders the error for a terminal: the message followed by the lines of `source` it comes
+    /// from, with a caret under the offending instruction.
+    ///
+    /// An unmatched bracket also points at where its match was expected. Errors without a
+    /// position are rendered as a single line.
+    /// ```
+    /// # use bf_lib::Exec;
+    /// let source = "+[>\n+]]";
+    /// let err = Exec::prog(source).interpret().unwrap_err();
+    /// assert_eq!(
+    ///     err.render(source),
+    ///     "error: unmatched bracket
+    ///  --> 2:3
+    ///   |
+    /// 1 | +[>
+    ///   |  - last `[`, already closed
+    /// 2 | +]]
+    ///   |   ^ no `[` left to close
+    /// "
+    /// );
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let (title, labels) = match self {
+            Error::Syntax(pos) => ("unmatched bracket", bracket_labels(source, *pos)),
+            Error::Runtime(RuntimeError::OutOfMemoryBounds(pos)) => (
+                "memory access out of bounds",
+                vec![Label {
+                    pos: *pos,
+                    text: "the pointer leaves the tape here",
+                }],
+            ),
+            Error::Runtime(RuntimeError::InputTooShort(pos)) => (
+                "input was not long enough",
+                vec![Label {
+                    pos: *pos,
+                    text: "no input left to read here",
+                }],
+            ),
+            Error::Runtime(RuntimeError::UndefinedProcedure(pos)) => (
+                "undefined procedure",
+                vec![Label {
+                    pos: *pos,
+                    text: "no procedure is named by the cell here",
+                }],
+            ),
+            Error::Runtime(RuntimeError::StackOverflow(pos)) => (
+                "too many nested calls",
+                vec![Label {
+                    pos: *pos,
+                    text: "this call doesn't fit on the stack",
+                }],
+            ),
+            Error::Runtime(RuntimeError::TooManyThreads(pos)) => (
+                "too many threads",
+                vec![Label {
+                    pos: *pos,
+                    text: "this fork is one too many",
+                }],
+            ),
+            Error::Asm(pos, msg) | Error::Dialect(pos, msg) => (
+                msg.as_str(),
+                vec![Label {
+                    pos: *pos,
+                    text: "here",
+                }],
+            ),
+            Error::InvalidToken(pos) => (
+                "invalid token",
+                vec![Label {
+                    pos: *pos,
+                    text: "not part of any command",
+                }],
+            ),
+            _ => return format!("error: {:#}\n", self),
+        };
+        let primary = labels[0].pos;
+        let mut sorted: Vec<(bool, &Label)> = labels
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (i == 0, l))
+            .collect();
+        sorted.sort_by_key(|(_, l)| l.pos.offset);
+        let width = sorted.last().unwrap().1.pos.line.to_string().len();
+
+        let mut out = format!("error: {}\n", title);
+        writeln!(
+            out,
+            "{:w$}--> {}:{}",
+            "",
+            primary.line,
+            primary.column,
+            w = width
+        )
+        .unwrap();
+        writeln!(out, "{:w$} |", "", w = width).unwrap();
+        let mut last_line = None;
+        for (is_primary, label) in sorted {
+            let line = source.lines().nth(label.pos.line - 1).unwrap_or("");
+            match last_line {
+                Some(l) if l == label.pos.line => (),
+                Some(l) if l + 1 < label.pos.line => {
+                    writeln!(out, "...").unwrap();
+                    writeln!(out, "{:>w$} | {}", label.pos.line, line, w = width).unwrap()
+                }
+                _ => writeln!(out, "{:>w$} | {}", label.pos.line, line, w = width).unwrap(),
+            }
+            last_line = Some(label.pos.line);
+            // keep the tabs so the caret lines up
+            let pad: String = line
+                .chars()
+                .take(label.pos.column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let mark = if is_primary { '^' } else { '-' };
+            writeln!(out, "{:w$} | {}{} {}", "", pad, mark, label.text, w = width).unwrap();
+        }
+        out
+    }
+}
+
+/// Points at the unmatched bracket at `pos` and where its match should have been.
+fn bracket_labels(source: &str, pos: Position) -> Vec<Label> {
+    match source.as_bytes().get(pos.offset) {
+        Some(b'[') => vec![
+            Label {
+                pos,
+                text: "this `[` is never closed",
+            },
+            Label {
+                pos: Position::new(source, source.trim_end().len()),
+                text: "expected `]` before the end",
+            },
+        ],
+        Some(b']') => {
+            let mut labels = vec![Label {
+                pos,
+                text: "no `[` left to close",
+            }];
+            if let Some(open) = source.as_bytes()[..pos.offset]
+                .iter()
+                .rposition(|&b| b == b'[')
+            {
+                labels.push(Label {
+                    pos: Position::new(source, open),
+                    text: "last `[`, already closed",
+                })
+            }
+            labels
+        }
+        // written in another dialect
+        _ => vec![Label {
+            pos,
+            text: "this bracket has no match",
+        }],
+    }
+}
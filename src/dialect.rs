@@ -0,0 +1,228 @@
+use crate::{
+    bf::{commands, is_command, Extensions},
+    Error, Position, Span,
+};
+use std::{fs, path::Path};
+
+/// Ook! words of each brainfuck command, as the punctuation after `Ook`.
+const OOK: [(u8, [u8; 2]); 8] = [
+    (b'>', *b".?"),
+    (b'<', *b"?."),
+    (b'+', *b".."),
+    (b'-', *b"!!"),
+    (b'.', *b"!."),
+    (b',', *b".!"),
+    (b'[', *b"!?"),
+    (b']', *b"?!"),
+];
+
+/// Commands written on each line by [`from_brainfuck`].
+///
+/// [`from_brainfuck`]: enum.Dialect.html#method.from_brainfuck
+const LINE: usize = 8;
+
+/// Language a program is written in, see [`Exec::dialect`].
+///
+/// Every dialect is parsed into the same instructions, so they all run on both backends.
+///
+/// [`Exec::dialect`]: struct.Exec.html#method.dialect
+/// ```
+/// # use bf_lib::{Dialect, Exec};
+/// let ook = Dialect::Ook.from_brainfuck("+++[>++<-]>.");
+/// assert!(ook.starts_with("Ook. Ook. Ook. Ook. Ook. Ook. Ook! Ook?"));
+/// assert_eq!(Dialect::Ook.to_brainfuck(&ook).unwrap(), "+++[>++<-]>.");
+///
+/// let output = Exec::prog(&ook).dialect(Dialect::Ook).interpret().unwrap();
+/// assert_eq!(output, "\u{6}");
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Brainfuck,
+    /// Every command is a pair of `Ook.`, `Ook?` or `Ook!` words, anything else is a comment.
+    Ook,
+    /// Brainfuck with other tokens for the commands.
+    Custom(Substitution),
+}
+
+impl Dialect {
+    /// Brainfuck commands of `source`, along with where they are.
+    pub(crate) fn tokens(&self, source: &str, ext: &Extensions) -> Result<Vec<(Span, u8)>, Error> {
+        match self {
+            Dialect::Brainfuck => Ok(commands(source, ext)),
+            Dialect::Ook => ook_tokens(source),
+            Dialect::Custom(sub) => Ok(sub.tokens(source, ext)),
+        }
+    }
+
+    /// Translates a program written in this dialect to brainfuck, leaving out the comments.
+    ///
+    /// Brainfuck programs are returned unchanged. Fails with [`Error::InvalidToken`] at the first
+    /// Ook! word that isn't part of a command.
+    ///
+    /// [`Error::InvalidToken`]: enum.Error.html#variant.InvalidToken
+    pub fn to_brainfuck(&self, source: &str) -> Result<String, Error> {
+        self.translate(source, &Extensions::default())
+    }
+
+    /// Same as [`to_brainfuck`], keeping the extension commands.
+    ///
+    /// [`to_brainfuck`]: enum.Dialect.html#method.to_brainfuck
+    pub(crate) fn translate(&self, source: &str, ext: &Extensions) -> Result<String, Error> {
+        match self {
+            Dialect::Brainfuck => Ok(source.to_string()),
+            _ => {
+                let tokens = self.tokens(source, ext)?;
+                Ok(tokens.into_iter().map(|(_, b)| b as char).collect())
+            }
+        }
+    }
+
+    /// Translates a brainfuck program to this dialect, leaving out the comments.
+    ///
+    /// Commands a [`Substitution`] has no token for are left out too.
+    ///
+    /// [`Substitution`]: struct.Substitution.html
+    pub fn from_brainfuck(&self, bf: &str) -> String {
+        let words: Vec<String> = match self {
+            Dialect::Brainfuck => return bf.to_string(),
+            Dialect::Ook => bf
+                .bytes()
+                .filter_map(|b| OOK.iter().find(|(c, _)| *c == b))
+                .map(|(_, [a, b])| format!("Ook{} Ook{}", *a as char, *b as char))
+                .collect(),
+            Dialect::Custom(sub) => bf
+                .bytes()
+                .filter_map(|b| sub.tokens.iter().find(|(_, c)| *c == b))
+                .map(|(t, _)| t.clone())
+                .collect(),
+        };
+        let mut out: Vec<String> = words.chunks(LINE).map(|l| l.join(" ")).collect();
+        out.push(String::new());
+        out.join("\n")
+    }
+}
+
+/// Tokens of a [`Dialect::Custom`], each standing for a brainfuck command.
+///
+/// They're usually loaded from a small config file: every line is a command followed by its
+/// token, empty lines and lines starting with `;` are ignored. A command can have more than one
+/// token, where two overlap the longest one is used. Anything else in the program is a comment.
+///
+/// [`Dialect::Custom`]: enum.Dialect.html#variant.Custom
+/// ```
+/// # use bf_lib::{Dialect, Exec, Substitution};
+/// let config = "; Blub
+/// > Blub. Blub?
+/// < Blub? Blub.
+/// + Blub. Blub.
+/// - Blub! Blub!
+/// . Blub! Blub.
+/// , Blub. Blub!
+/// [ Blub! Blub?
+/// ] Blub? Blub!";
+/// let blub = Dialect::Custom(Substitution::parse(config).unwrap());
+///
+/// let prog = blub.from_brainfuck("++++[>++++++++++<-]>+.");
+/// assert_eq!(Exec::prog(&prog).dialect(blub).interpret().unwrap(), ")");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Substitution {
+    /// Tokens and their commands, longest first
+    tokens: Vec<(String, u8)>,
+}
+
+impl Substitution {
+    /// Reads the tokens from the text of a config file.
+    ///
+    /// Fails with an [`Error::Dialect`] if a line doesn't start with a command or has no token.
+    ///
+    /// [`Error::Dialect`]: enum.Error.html#variant.Dialect
+    pub fn parse(config: &str) -> Result<Substitution, Error> {
+        let all = Extensions {
+            debug: true,
+            procedures: true,
+            fork: true,
+        };
+        let mut tokens = vec![];
+        let mut next = 0;
+        for line in config.split_inclusive('\n') {
+            // offset of the first character of the line that isn't a space
+            let start = next + line.len() - line.trim_start().len();
+            next += line.len();
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            let error =
+                |offset, msg: &str| Error::Dialect(Position::new(config, offset), msg.to_string());
+            let cmd = line.as_bytes()[0];
+            if !is_command(cmd, &all) {
+                return Err(error(start, "expected a command"));
+            }
+            let token = line[1..].trim();
+            if token.is_empty() {
+                return Err(error(start + 1, "expected a token after the command"));
+            }
+            tokens.push((token.to_string(), cmd));
+        }
+        // the first token found is the longest
+        tokens.sort_by_key(|(t, _)| std::cmp::Reverse(t.len()));
+        Ok(Substitution { tokens })
+    }
+
+    /// Reads the tokens from a config file, see [`parse`].
+    ///
+    /// [`parse`]: struct.Substitution.html#method.parse
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Substitution, Error> {
+        Substitution::parse(&fs::read_to_string(path).map_err(Error::Io)?)
+    }
+
+    /// Finds the tokens in `source`, skipping the comments in between.
+    fn tokens(&self, source: &str, ext: &Extensions) -> Vec<(Span, u8)> {
+        let mut found = vec![];
+        let mut start = 0;
+        while let Some(next) = source[start..].chars().next() {
+            let token = self
+                .tokens
+                .iter()
+                .filter(|(_, c)| is_command(*c, ext))
+                .find(|(t, _)| source[start..].starts_with(t.as_str()));
+            match token {
+                Some((t, c)) => {
+                    let end = start + t.len();
+                    found.push((Span { start, end }, *c));
+                    start = end;
+                }
+                None => start += next.len_utf8(),
+            }
+        }
+        found
+    }
+}
+
+/// Finds the `Ook` words of `source` and pairs them into commands.
+fn ook_tokens(source: &str) -> Result<Vec<(Span, u8)>, Error> {
+    let bytes = source.as_bytes();
+    let words: Vec<(usize, u8)> = (0..bytes.len())
+        .filter(|&i| bytes[i..].starts_with(b"Ook"))
+        .filter_map(|i| match bytes.get(i + 3) {
+            Some(&p) if p == b'.' || p == b'?' || p == b'!' => Some((i, p)),
+            _ => None,
+        })
+        .collect();
+    words
+        .chunks(2)
+        .map(|pair| {
+            let (start, a) = pair[0];
+            let invalid = || Error::InvalidToken(Position::new(source, start));
+            let &(end, b) = pair.get(1).ok_or_else(invalid)?;
+            let &(cmd, _) = OOK.iter().find(|(_, w)| *w == [a, b]).ok_or_else(invalid)?;
+            let span = Span {
+                start,
+                end: end + 4,
+            };
+            Ok((span, cmd))
+        })
+        .collect()
+}
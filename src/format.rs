@@ -0,0 +1,306 @@
+use crate::{
+    bf::{is_command, AsInst, Extensions, Instruction},
+    check_brackets, Error,
+};
+
+/// Options of [`fmt`].
+///
+/// [`fmt`]: fn.fmt.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Style {
+    /// Keep the comments, each line of text on its own line. Otherwise they're removed.
+    pub comments: bool,
+    /// Spaces added for each level of nesting.
+    pub indent: usize,
+    /// Lines are wrapped past this many characters, unless a single run is longer.
+    pub width: usize,
+    /// Formats `(`, `)` and `:` as commands, see [`Exec::procedures`]. Otherwise they're comments.
+    ///
+    /// [`Exec::procedures`]: struct.Exec.html#method.procedures
+    pub procedures: bool,
+    /// Formats `Y` as a command, see [`Exec::fork`]. Otherwise it's a comment.
+    ///
+    /// [`Exec::fork`]: struct.Exec.html#method.fork
+    pub fork: bool,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            comments: true,
+            indent: 4,
+            width: 80,
+            procedures: false,
+            fork: false,
+        }
+    }
+}
+
+/// Reformats a program in a canonical style.
+///
+/// Runs of the same kind of command (`+-`, `<>`, `.`, `,`, `#`, `:` and `Y`) are grouped and
+/// separated by a space. Loops and procedures with only commands in them stay on one line when
+/// they fit, others are written as a block with the body indented. Formatting again gives back
+/// the same text.
+/// ```
+/// # use bf_lib::Style;
+/// let prog = "++ add two [>+++ times three [>+<-] <-] >.";
+/// assert_eq!(
+///     bf_lib::fmt(prog, &Style::default()).unwrap(),
+///     "++
+/// add two
+/// [
+///     > +++
+///     times three
+///     [> + < -] < -
+/// ]
+/// > .
+/// "
+/// );
+///
+/// let style = Style { comments: false, ..Style::default() };
+/// assert_eq!(bf_lib::fmt(prog, &style).unwrap(), "++ [\n    > +++ [> + < -] < -\n]\n> .\n");
+/// ```
+pub fn fmt(program: &str, style: &Style) -> Result<String, Error> {
+    check_brackets(program)?;
+    // `#` is kept even if it's a comment when running
+    let ext = Extensions {
+        debug: true,
+        procedures: style.procedures,
+        fork: style.fork,
+    };
+    if style.procedures {
+        // the parentheses have to match too
+        program.to_inst(&ext)?;
+    }
+    let nodes = parse(program, &ext, style.comments);
+    let mut printer = Printer {
+        style,
+        out: String::new(),
+        line: String::new(),
+        depth: 0,
+    };
+    printer.nodes(&nodes);
+    printer.flush();
+    Ok(printer.out)
+}
+
+/// Prints the shortest equivalent program: comments, instructions that cancel out and loops that
+/// can never run are removed.
+///
+/// The tape wraps around, so each run of `+` or `-` is written in whichever direction is
+/// shorter. Moves and changes after the last loop or output are dropped, they can't be observed.
+/// ```
+/// let prog = "Set: ++--- - [-][ dead, loop ]> +++ <<>. <+";
+/// assert_eq!(bf_lib::minify(prog).unwrap(), "--[-]>+++<.");
+/// ```
+pub fn minify(program: &str) -> Result<String, Error> {
+    check_brackets(program)?;
+    let prog = program.to_inst(&Extensions::default())?;
+    let mut ops: Vec<Op> = Vec::new();
+    let mut p = 0;
+    while p < prog.insts.len() {
+        let op = match prog.insts[p] {
+            Instruction::Add(x) => Op::Add(x as u8),
+            Instruction::Sub(x) => Op::Add((x as u8).wrapping_neg()),
+            Instruction::Right(x) => Op::Move(x as i64),
+            Instruction::Left(x) => Op::Move(-(x as i64)),
+            Instruction::Print => Op::Print,
+            Instruction::Read => Op::Read,
+            Instruction::LoopStart(end) => {
+                // the cell is zero after a loop, and every cell is zero at the start
+                let zero = matches!(ops.last(), Some(Op::Close))
+                    || ops.iter().all(|op| matches!(op, Op::Move(_) | Op::Print));
+                if zero {
+                    p = end + 1;
+                    continue;
+                }
+                Op::Open
+            }
+            Instruction::LoopEnd(_) => Op::Close,
+            Instruction::Debug
+            | Instruction::ProcStart(_)
+            | Instruction::ProcEnd
+            | Instruction::Call
+            | Instruction::Fork => unreachable!(),
+        };
+        match (ops.last_mut(), op) {
+            (Some(Op::Add(a)), Op::Add(b)) => *a = a.wrapping_add(b),
+            (Some(Op::Move(a)), Op::Move(b)) => *a += b,
+            (_, op) => ops.push(op),
+        }
+        if let Some(Op::Add(0)) | Some(Op::Move(0)) = ops.last() {
+            ops.pop();
+        }
+        p += 1;
+    }
+    while let Some(Op::Add(_)) | Some(Op::Move(_)) = ops.last() {
+        ops.pop();
+    }
+
+    let mut out = String::new();
+    for op in ops {
+        match op {
+            Op::Add(x) if x <= 128 => out.push_str(&"+".repeat(x as usize)),
+            Op::Add(x) => out.push_str(&"-".repeat(x.wrapping_neg() as usize)),
+            Op::Move(x) if x > 0 => out.push_str(&">".repeat(x as usize)),
+            Op::Move(x) => out.push_str(&"<".repeat(-x as usize)),
+            Op::Print => out.push('.'),
+            Op::Read => out.push(','),
+            Op::Open => out.push('['),
+            Op::Close => out.push(']'),
+        }
+    }
+    Ok(out)
+}
+
+/// Instruction of a minified program, changes are modulo 256.
+enum Op {
+    Add(u8),
+    Move(i64),
+    Print,
+    Read,
+    Open,
+    Close,
+}
+
+enum Node<'a> {
+    /// Commands of the same kind
+    Run(String),
+    /// Loop or procedure, by its opening bracket
+    Block(u8, Vec<Node<'a>>),
+    Comment(&'a str),
+}
+
+/// Kind of command, runs are made of commands of the same kind.
+fn kind(b: u8) -> u8 {
+    match b {
+        b'-' => b'+',
+        b'<' => b'>',
+        b => b,
+    }
+}
+
+/// Closing bracket of a block.
+fn close(open: u8) -> char {
+    match open {
+        b'(' => ')',
+        _ => ']',
+    }
+}
+
+/// Builds the tree of loops and procedures, the brackets have to match.
+fn parse<'a>(program: &'a str, ext: &Extensions, comments: bool) -> Vec<Node<'a>> {
+    let mut stack: Vec<(u8, Vec<Node>)> = vec![(0, Vec::new())];
+    let mut comment = None;
+    for (pos, b) in program.bytes().enumerate() {
+        if !is_command(b, ext) {
+            comment.get_or_insert(pos);
+            continue;
+        }
+        let nodes = &mut stack.last_mut().unwrap().1;
+        if let Some(start) = comment.take() {
+            let text = program[start..pos].trim();
+            if comments && !text.is_empty() {
+                nodes.push(Node::Comment(text))
+            }
+        }
+        match b {
+            b'[' | b'(' => stack.push((b, Vec::new())),
+            b']' | b')' => {
+                let (open, body) = stack.pop().unwrap();
+                stack.last_mut().unwrap().1.push(Node::Block(open, body))
+            }
+            _ => match nodes.last_mut() {
+                Some(Node::Run(run)) if kind(run.as_bytes()[0]) == kind(b) => run.push(b as char),
+                _ => nodes.push(Node::Run((b as char).to_string())),
+            },
+        }
+    }
+    let (_, mut nodes) = stack.pop().unwrap();
+    if let Some(start) = comment {
+        let text = program[start..].trim();
+        if comments && !text.is_empty() {
+            nodes.push(Node::Comment(text))
+        }
+    }
+    nodes
+}
+
+struct Printer<'a> {
+    style: &'a Style,
+    out: String,
+    /// Line being filled, without the indentation
+    line: String,
+    depth: usize,
+}
+
+impl Printer<'_> {
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Run(run) => self.word(run),
+                Node::Comment(text) => {
+                    self.flush();
+                    for l in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                        self.line.push_str(l);
+                        self.flush();
+                    }
+                }
+                Node::Block(open, body) => match inline(*open, body) {
+                    Some(text) if self.fits(&text) => self.word(&text),
+                    _ => {
+                        self.word(&(*open as char).to_string());
+                        self.flush();
+                        self.depth += 1;
+                        self.nodes(body);
+                        self.flush();
+                        self.depth -= 1;
+                        self.word(&close(*open).to_string());
+                        self.flush();
+                    }
+                },
+            }
+        }
+    }
+
+    /// Whether `word` fits on a line of its own.
+    fn fits(&self, word: &str) -> bool {
+        self.depth * self.style.indent + word.len() <= self.style.width
+    }
+
+    /// Adds a group of commands to the line, starting a new one if it's full.
+    fn word(&mut self, word: &str) {
+        if !self.line.is_empty() {
+            let len = self.depth * self.style.indent + self.line.len() + 1 + word.len();
+            if len > self.style.width {
+                self.flush()
+            } else {
+                self.line.push(' ')
+            }
+        }
+        self.line.push_str(word)
+    }
+
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            let indent = " ".repeat(self.depth * self.style.indent);
+            self.out.push_str(&indent);
+            self.out.push_str(&self.line);
+            self.out.push('\n');
+            self.line.clear()
+        }
+    }
+}
+
+/// The block on a single line, if it only contains commands.
+fn inline(open: u8, body: &[Node]) -> Option<String> {
+    let mut runs = Vec::new();
+    for node in body {
+        match node {
+            Node::Run(run) => runs.push(run.as_str()),
+            _ => return None,
+        }
+    }
+    Some(format!("{}{}{}", open as char, runs.join(" "), close(open)))
+}
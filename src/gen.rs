@@ -0,0 +1,136 @@
+//! Generators of brainfuck programs.
+
+/// Largest loop counter tried when setting up the cells.
+const MAX_FACTOR: u8 = 16;
+
+/// How many bytes of the text are used to choose the cells.
+const SAMPLE: usize = 256;
+
+/// Most cells the search starts with.
+const MAX_CELLS: usize = 8;
+
+/// Builds a short program printing `text`.
+///
+/// A first loop fills a few cells with multiples of the same number, then each byte is printed
+/// from the cell holding the closest value, which keeps it for the next ones. Every loop size is
+/// tried and the shortest program is returned.
+/// ```
+/// # use bf_lib::{gen, Exec};
+/// let prog = gen::print_string(b"Hello, World!");
+/// assert!(prog.len() < 120);
+/// assert_eq!(Exec::prog(&prog).interpret().unwrap(), "Hello, World!");
+/// ```
+pub fn print_string(text: &[u8]) -> String {
+    (2..=MAX_FACTOR)
+        .map(|f| with_factor(text, f))
+        .chain(std::iter::once(with_cells(text, String::new(), vec![0])))
+        .min_by_key(String::len)
+        .unwrap()
+}
+
+/// Sets up the cells with a loop running `factor` times. Starting from a cell for each of the
+/// multiples of `factor` closest to the most bytes of the text, cells are removed or moved to a
+/// nearby multiple as long as it makes the program for the start of the text shorter.
+fn with_factor(text: &[u8], factor: u8) -> String {
+    let full = text;
+    let text = &text[..text.len().min(SAMPLE)];
+    let max = (255 / factor as u16) as u8;
+    // the most used multiples
+    let mut uses = vec![0usize; max as usize + 1];
+    for &b in text {
+        uses[((b as u16 + factor as u16 / 2) / factor as u16).min(max as u16) as usize] += 1;
+    }
+    let mut mults: Vec<u8> = (1..=max).filter(|&m| uses[m as usize] > 0).collect();
+    mults.sort_by_key(|&m| std::cmp::Reverse(uses[m as usize]));
+    mults.truncate(MAX_CELLS);
+    let mut best = with_mults(text, factor, &mults);
+    loop {
+        let mut tries = Vec::new();
+        for i in 0..mults.len() {
+            let mut fewer = mults.clone();
+            fewer.remove(i);
+            tries.push(fewer);
+            for m in [
+                mults[i].saturating_sub(1),
+                mults[i].saturating_add(1).min(max),
+            ] {
+                if m > 0 && !mults.contains(&m) {
+                    let mut moved = mults.clone();
+                    moved[i] = m;
+                    tries.push(moved);
+                }
+            }
+        }
+        match tries
+            .into_iter()
+            .map(|m| (with_mults(text, factor, &m), m))
+            .min_by_key(|(prog, _)| prog.len())
+        {
+            Some((prog, m)) if prog.len() < best.len() => {
+                best = prog;
+                mults = m;
+            }
+            _ => return with_mults(full, factor, &mults),
+        }
+    }
+}
+
+/// Program using cells set to `factor` times each of `mults`.
+fn with_mults(text: &[u8], factor: u8, mults: &[u8]) -> String {
+    if mults.is_empty() {
+        return with_cells(text, String::new(), vec![0]);
+    }
+    // cells used first come first
+    let mut first_use = vec![usize::MAX; mults.len()];
+    for (pos, &b) in text.iter().enumerate().rev() {
+        let d = |i: &usize| (mults[*i] as i16 * factor as i16 - b as i16).abs();
+        first_use[(0..mults.len()).min_by_key(d).unwrap()] = pos;
+    }
+    let mut order: Vec<usize> = (0..mults.len()).collect();
+    order.sort_by_key(|&i| first_use[i]);
+    let mults: Vec<u8> = order.into_iter().map(|i| mults[i]).collect();
+    let mut init = "+".repeat(factor as usize) + "[";
+    for m in &mults {
+        init.push('>');
+        init.push_str(&"+".repeat(*m as usize));
+    }
+    init.push_str(&"<".repeat(mults.len()));
+    init.push_str("-]");
+    let cells = std::iter::once(0)
+        .chain(mults.iter().map(|m| m * factor))
+        .collect();
+    with_cells(text, init, cells)
+}
+
+/// Prints every byte from the cheapest cell to reach, starting at cell 0 after `init`.
+fn with_cells(text: &[u8], init: String, mut cells: Vec<u8>) -> String {
+    let mut out = init;
+    let mut ptr = 0;
+    for &b in text {
+        let cost = |c: usize| {
+            let up = b.wrapping_sub(cells[c]);
+            (c as isize - ptr as isize).unsigned_abs() + up.min(up.wrapping_neg()) as usize
+        };
+        let c = (0..cells.len()).min_by_key(|&c| cost(c)).unwrap();
+        if c > ptr {
+            out.push_str(&">".repeat(c - ptr))
+        } else {
+            out.push_str(&"<".repeat(ptr - c))
+        }
+        out.push_str(&change(cells[c], b));
+        out.push('.');
+        cells[c] = b;
+        ptr = c;
+    }
+    out
+}
+
+/// Shortest run of `+` or `-` turning `from` into `to`, the cells wrap around.
+fn change(from: u8, to: u8) -> String {
+    let up = to.wrapping_sub(from);
+    if up <= 128 {
+        "+".repeat(up as usize)
+    } else {
+        "-".repeat(up.wrapping_neg() as usize)
+    }
+}
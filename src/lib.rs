@@ -6,62 +6,329 @@
 //!
 //! [`subprocess`]: https://crates.io/crates/subprocess
 
-use std::{error, fmt, time, path::PathBuf};
+use std::{
+    error, fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time,
+};
 
 /// Possible errors encountered while running the program.
+///
+/// The alternate format `{:#}` leaves out the "Error, I didn't quite get that." line, and
+/// [`render`] shows where the error is in the source.
+///
+/// [`render`]: enum.Error.html#method.render
 #[derive(Debug)]
 pub enum Error {
     Compile(String),
     Runtime(RuntimeError),
     Subprocess(subprocess::PopenError),
-    Syntax(usize),
+    Syntax(Position),
     Timeout,
+    StepLimitExceeded,
+    Cancelled,
+    Io(std::io::Error),
+    Unsupported(&'static str),
+    /// Invalid program given to [`asm::compile`].
+    ///
+    /// [`asm::compile`]: asm/fn.compile.html
+    Asm(Position, String),
+    /// Word of a [`Dialect`] program that isn't part of any command.
+    ///
+    /// [`Dialect`]: enum.Dialect.html
+    InvalidToken(Position),
+    /// Invalid config given to [`Substitution::parse`], the position is in the config.
+    ///
+    /// [`Substitution::parse`]: struct.Substitution.html#method.parse
+    Dialect(Position, String),
+    /// Snapshot given to [`Exec::resume`] that doesn't match its program, or that no run of it
+    /// could have saved.
+    ///
+    /// [`Exec::resume`]: struct.Exec.html#method.resume
+    InvalidSnapshot,
 }
 
 impl error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let pre = "Error, I didn't quite get that.\n";
+        let pre = if f.alternate() {
+            ""
+        } else {
+            "Error, I didn't quite get that.\n"
+        };
         match self {
             Error::Compile(s) => write!(f, "{}rustc error: {}", pre, s),
             Error::Runtime(e) => write!(f, "{}Runtime error: {}", pre, e),
             Error::Subprocess(e) => write!(f, "{}rustc error: {}", pre, e),
             Error::Syntax(p) => write!(f, "{}Unmatched bracket at {}.", pre, p),
             Error::Timeout => write!(f, "{}Executable timed out.", pre),
+            Error::StepLimitExceeded => write!(f, "{}Step limit exceeded.", pre),
+            Error::Cancelled => write!(f, "{}Execution was cancelled.", pre),
+            Error::Io(e) => write!(f, "{}I/O error: {}", pre, e),
+            Error::Unsupported(s) => write!(f, "{}Not supported: {}.", pre, s),
+            Error::Asm(p, s) => write!(f, "{}Assembly error at {}: {}.", pre, p, s),
+            Error::InvalidToken(p) => write!(f, "{}Invalid token at {}.", pre, p),
+            Error::Dialect(p, s) => write!(f, "{}Dialect error at {}: {}.", pre, p, s),
+            Error::InvalidSnapshot => write!(f, "{}Snapshot doesn't match the program.", pre),
+        }
+    }
+}
+
+/// Position of a byte in the source.
+///
+/// ```
+/// # use bf_lib::Position;
+/// let pos = Position::new("+[\n>]]", 5);
+/// assert_eq!((pos.line, pos.column), (2, 3));
+/// assert_eq!(pos.to_string(), "line 2, column 3");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset from the start of the source.
+    pub offset: usize,
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Column in characters, starting from 1.
+    pub column: usize,
+}
+
+impl Position {
+    /// Finds the line and column of the byte at `offset` in `source`.
+    pub fn new(source: &str, offset: usize) -> Position {
+        let before = &source.as_bytes()[..offset.min(source.len())];
+        let start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        Position {
+            offset,
+            line: before.iter().filter(|&&b| b == b'\n').count() + 1,
+            column: chars(&before[start..]) + 1,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Where each line of a source starts, to look up many offsets without rescanning it.
+pub(crate) struct Lines {
+    starts: Vec<usize>,
+    /// Characters before every multiple of `BLOCK` bytes, columns only count the bytes after them
+    chars: Vec<usize>,
+}
+
+impl Lines {
+    const BLOCK: usize = 64;
+
+    pub(crate) fn new(source: &str) -> Lines {
+        let newlines = source.match_indices('\n').map(|(i, _)| i + 1);
+        let chars = source.as_bytes().chunks(Lines::BLOCK).scan(0, |n, block| {
+            *n += chars(block);
+            Some(*n)
+        });
+        Lines {
+            starts: std::iter::once(0).chain(newlines).collect(),
+            chars: std::iter::once(0).chain(chars).collect(),
+        }
+    }
+
+    /// Index of the line containing the byte at `offset`, starting from 0.
+    pub(crate) fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&s| s <= offset) - 1
+    }
+
+    /// Same as [`Position::new`], `source` has to be the one the lines were found in.
+    ///
+    /// [`Position::new`]: struct.Position.html#method.new
+    pub(crate) fn position(&self, source: &str, offset: usize) -> Position {
+        let end = offset.min(source.len());
+        let line = self.line_of(end);
+        let chars_before = |offset: usize| {
+            let block = offset / Lines::BLOCK;
+            self.chars[block] + chars(&source.as_bytes()[block * Lines::BLOCK..offset])
+        };
+        Position {
+            offset,
+            line: line + 1,
+            column: chars_before(end) - chars_before(self.starts[line]) + 1,
+        }
+    }
+}
+
+/// Number of characters in the bytes of a string.
+fn chars(bytes: &[u8]) -> usize {
+    // continuation bytes are part of the same character
+    bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count()
+}
+
+/// Byte range of the source an instruction was parsed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// State printed by the `#` instruction, see [`Exec::debug`].
+///
+/// Its [`Display`] implementation is the text that's printed to stderr when no callback is set.
+///
+/// [`Exec::debug`]: struct.Exec.html#method.debug
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    /// Byte offset of the `#` in the source.
+    pub pos: usize,
+    /// Position of the tape pointer.
+    pub pointer: usize,
+    /// Index of the first cell in `cells`.
+    pub first: usize,
+    /// Values of the cells around the pointer.
+    pub cells: Vec<u8>,
+}
+
+/// Callback receiving the output of `#`.
+pub(crate) type DumpFn = Box<dyn FnMut(&Dump) + Send>;
+
+impl Dump {
+    pub(crate) fn new(pos: usize, pointer: usize, mem: &[u8]) -> Dump {
+        let first = pointer.saturating_sub(bf::DUMP_RADIUS);
+        let end = (pointer + bf::DUMP_RADIUS + 1).min(mem.len());
+        Dump {
+            pos,
+            pointer,
+            first,
+            cells: mem[first..end].to_vec(),
         }
     }
+
+    /// Reads back a line printed by the Display implementation.
+    pub(crate) fn parse(line: &str) -> Option<Dump> {
+        let (pos, rest) = line.strip_prefix('#')?.split_once(": pointer ")?;
+        let (pointer, rest) = rest.split_once(", cells ")?;
+        let (first, rest) = rest.split_once("..")?;
+        let (_, cells) = rest.split_once(':')?;
+        Some(Dump {
+            pos: pos.parse().ok()?,
+            pointer: pointer.parse().ok()?,
+            first: first.parse().ok()?,
+            cells: cells
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{}: pointer {}, cells {}..{}:",
+            self.pos,
+            self.pointer,
+            self.first,
+            self.first + self.cells.len()
+        )?;
+        for c in &self.cells {
+            write!(f, " {}", c)?;
+        }
+        Ok(())
+    }
 }
 
 /// Possible runtime errors encountered while running the program.
+///
+/// Errors caused by an instruction carry its position in the source.
 #[derive(Debug, PartialEq)]
 pub enum RuntimeError {
-    OutOfMemoryBounds,
-    InputTooShort,
+    OutOfMemoryBounds(Position),
+    InputTooShort(Position),
+    /// `:` called a procedure that wasn't defined.
+    UndefinedProcedure(Position),
+    /// Too many procedure calls were running at once.
+    StackOverflow(Position),
+    /// `Y` would start more than 256 threads.
+    TooManyThreads(Position),
     Signal,
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RuntimeError::OutOfMemoryBounds => write!(f, "access memory out of bounds"),
-            RuntimeError::InputTooShort => write!(f, "input was not long enough"),
+            RuntimeError::OutOfMemoryBounds(p) => write!(f, "access memory out of bounds at {}", p),
+            RuntimeError::InputTooShort(p) => write!(f, "input was not long enough at {}", p),
+            RuntimeError::UndefinedProcedure(p) => write!(f, "undefined procedure called at {}", p),
+            RuntimeError::StackOverflow(p) => write!(f, "too many nested calls at {}", p),
+            RuntimeError::TooManyThreads(p) => write!(f, "too many threads started at {}", p),
             RuntimeError::Signal => write!(f, "executable was probably killed by a signal"),
         }
     }
 }
 
+/// Handle used to stop a running program from another thread.
+///
+/// Clones share the same flag, so one can be passed to [`Exec::cancel`] and the other kept to call
+/// [`cancel`] later: the run will end with an [`Error::Cancelled`].
+///
+/// [`Exec::cancel`]: struct.Exec.html#method.cancel
+/// [`cancel`]: struct.CancelToken.html#method.cancel
+/// [`Error::Cancelled`]: enum.Error.html#variant.Cancelled
+/// ```
+/// # use bf_lib::{CancelToken, Error, Exec};
+/// # use std::{thread, time::Duration};
+/// let token = CancelToken::new();
+/// let handle = token.clone();
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_millis(50));
+///     handle.cancel();
+/// });
+/// let res = Exec::prog("+[]").cancel(token).interpret();
+///
+/// assert!(matches!(res, Err(Error::Cancelled)));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a new token that hasn't been cancelled.
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Stops every run using this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    /// Checks if [`cancel`] has been called.
+    ///
+    /// [`cancel`]: struct.CancelToken.html#method.cancel
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 /// Interface for running brainfuck code.
 ///
-/// The [`prog`] method returns an instance with the default options (no timeout, input or
-/// temporary file path)
+/// The [`prog`] method returns an instance with the default options (no timeout, step limit,
+/// input or temporary file path)
 ///
-/// [`input`], [`timeout`] and [`tmpdir`] are used to change the default values, the program can
-/// then be run by calling [`run`], [`transpile`] or [`interpret`].
+/// [`input`], [`timeout`], [`max_steps`], [`cancel`] and [`tmpdir`] are used to change the
+/// default values, the program can then be run by calling [`run`], [`transpile`] or
+/// [`interpret`].
 ///
 /// [`prog`]: struct.Exec.html#method.prog
 /// [`input`]: struct.Exec.html#method.input
 /// [`timeout`]: struct.Exec.html#method.timeout
+/// [`max_steps`]: struct.Exec.html#method.max_steps
+/// [`cancel`]: struct.Exec.html#method.cancel
 /// [`tmpdir`]: struct.Exec.html#method.tmpdir
 /// [`run`]: struct.Exec.html#method.run
 /// [`transpile`]: struct.Exec.html#method.transpile
@@ -77,21 +344,56 @@ pub struct Exec {
     program: String,
     input: Option<String>,
     time: Option<time::Duration>,
+    steps: Option<u64>,
+    cancel: Option<CancelToken>,
     tmp_path: Option<PathBuf>,
+    ext: bf::Extensions,
+    dialect: Dialect,
+    real_threads: bool,
+    on_debug: Option<DumpFn>,
+    trace: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    resume: Option<Snapshot>,
 }
 
 impl Exec {
     /// Contructs a new `Exec`, configured to run `prog`.
-    /// By default it will be run without input, timeout or temporary file path (defaults to cwd).
+    /// By default it will be run without input, timeout, step limit or temporary file path
+    /// (defaults to cwd).
     pub fn prog(prog: &str) -> Exec {
         Exec {
             program: String::from(prog),
             input: None,
             time: None,
+            steps: None,
+            cancel: None,
             tmp_path: None,
+            ext: bf::Extensions::default(),
+            dialect: Dialect::default(),
+            real_threads: false,
+            on_debug: None,
+            trace: None,
+            checkpoint: None,
+            resume: None,
         }
     }
 
+    /// Contructs a new `Exec` that continues the run saved in `snapshot`.
+    ///
+    /// The program, its input and the enabled extensions are taken from the snapshot. Only the
+    /// interpreter can resume a run: [`run`] always uses it, [`transpile`] returns an
+    /// [`Error::Unsupported`]. The step limit counts the steps run before the snapshot too.
+    ///
+    /// [`run`]: struct.Exec.html#method.run
+    /// [`transpile`]: struct.Exec.html#method.transpile
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    pub fn resume(snapshot: Snapshot) -> Exec {
+        let mut exec = Exec::prog(&snapshot.source);
+        exec.ext = snapshot.ext;
+        exec.resume = Some(snapshot);
+        exec
+    }
+
     /// Sets the input for the program.
     pub fn input(self, input: Option<String>) -> Exec {
         Exec {
@@ -108,6 +410,186 @@ impl Exec {
         }
     }
 
+    /// Sets the maximum number of instructions the program can execute.
+    ///
+    /// Unlike [`timeout`] the limit doesn't depend on the machine load, the same program will
+    /// always be stopped at the same point with an [`Error::StepLimitExceeded`].
+    /// Runs of consecutive `+-` or `<>` count as a single instruction.
+    ///
+    /// [`timeout`]: struct.Exec.html#method.timeout
+    /// [`Error::StepLimitExceeded`]: enum.Error.html#variant.StepLimitExceeded
+    pub fn max_steps(self, steps: u64) -> Exec {
+        Exec {
+            steps: Some(steps),
+            ..self
+        }
+    }
+
+    /// Sets a token that can be used to stop the program while it's running.
+    ///
+    /// The interpreter stops with an [`Error::Cancelled`], the transpiler kills the executable.
+    ///
+    /// [`Error::Cancelled`]: enum.Error.html#variant.Cancelled
+    pub fn cancel(self, token: CancelToken) -> Exec {
+        Exec {
+            cancel: Some(token),
+            ..self
+        }
+    }
+
+    /// Sets the language the program is written in, brainfuck by default.
+    ///
+    /// Error positions and spans refer to the program as it was written.
+    /// ```
+    /// # use bf_lib::{Dialect, Exec};
+    /// let prog = "Ook. Ook. Ook. Ook. Ook! Ook.";
+    /// let output = Exec::prog(prog).dialect(Dialect::Ook).interpret().unwrap();
+    /// assert_eq!(output, "\u{2}");
+    /// ```
+    pub fn dialect(self, dialect: Dialect) -> Exec {
+        Exec { dialect, ..self }
+    }
+
+    /// Enables the `#` instruction, which prints the position of the pointer and the values of the
+    /// cells around it to stderr.
+    ///
+    /// When disabled (the default) `#` is a comment like any other character.
+    pub fn debug(mut self, enabled: bool) -> Exec {
+        self.ext.debug = enabled;
+        self
+    }
+
+    /// Enables pbrain's procedures: `(` defines the procedure named by the value of the cell, up
+    /// to the matching `)`, and `:` calls the one named by the current cell.
+    ///
+    /// Calling a procedure that isn't defined fails with a [`RuntimeError::UndefinedProcedure`].
+    /// When disabled (the default) the three characters are comments.
+    ///
+    /// [`RuntimeError::UndefinedProcedure`]: enum.RuntimeError.html#variant.UndefinedProcedure
+    /// ```
+    /// # use bf_lib::Exec;
+    /// // procedure 1 prints the cell two places to the right and adds 1 to it
+    /// let prog = "+(>>.+<<)>+++++[>++++++++++<-]<:::";
+    /// let output = Exec::prog(prog).procedures(true).interpret().unwrap();
+    /// assert_eq!(output, "234");
+    /// ```
+    pub fn procedures(mut self, enabled: bool) -> Exec {
+        self.ext.procedures = enabled;
+        self
+    }
+
+    /// Enables Brainfork's `Y` instruction, which forks the running thread.
+    ///
+    /// The threads share the tape, the input and the output. `Y` zeroes the current cell, the new
+    /// thread goes on after it with the pointer one cell to the right and that cell set to 1, so
+    /// `Y[...]` is only entered by the new thread. A thread also gets a copy of the running
+    /// procedure calls.
+    ///
+    /// By default the threads take turns running one instruction each, so every run of the
+    /// program is the same, see [`real_threads`] to run them at the same time. Only the
+    /// interpreter supports forking: [`run`] always uses it, [`transpile`] returns an
+    /// [`Error::Unsupported`]. When disabled (the default) `Y` is a comment.
+    ///
+    /// [`real_threads`]: struct.Exec.html#method.real_threads
+    /// [`run`]: struct.Exec.html#method.run
+    /// [`transpile`]: struct.Exec.html#method.transpile
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    /// ```
+    /// # use bf_lib::Exec;
+    /// // the first thread adds 3 to cell 1, the new one to cell 2, then both print
+    /// let output = Exec::prog("Y>+++.").fork(true).interpret().unwrap();
+    /// assert_eq!(output, "\u{3}\u{4}");
+    /// ```
+    pub fn fork(mut self, enabled: bool) -> Exec {
+        self.ext.fork = enabled;
+        self
+    }
+
+    /// Runs every thread started by `Y` on its own OS thread, see [`fork`].
+    ///
+    /// The threads run at the same time, so the output can change from one run to the other:
+    /// every instruction reads a cell and writes it back, and the writes of another thread in
+    /// between are lost. Tracing and checkpoints aren't supported, they return an
+    /// [`Error::Unsupported`].
+    ///
+    /// [`fork`]: struct.Exec.html#method.fork
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    /// ```
+    /// # use bf_lib::Exec;
+    /// let output = Exec::prog("Y[>+++.<-]").fork(true).real_threads(true).interpret();
+    /// assert_eq!(output.unwrap(), "\u{3}");
+    /// ```
+    pub fn real_threads(self, real_threads: bool) -> Exec {
+        Exec {
+            real_threads,
+            ..self
+        }
+    }
+
+    /// Enables the `#` instruction and sends every [`Dump`] to `f` instead of stderr.
+    ///
+    /// The interpreter calls `f` as soon as `#` is run, the transpiler once the executable has
+    /// exited.
+    ///
+    /// [`Dump`]: struct.Dump.html
+    /// ```
+    /// # use bf_lib::Exec;
+    /// # use std::sync::{Arc, Mutex};
+    /// let dumps = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = dumps.clone();
+    /// Exec::prog("+++>++#")
+    ///     .on_debug(move |d| sink.lock().unwrap().push(d.clone()))
+    ///     .interpret()
+    ///     .unwrap();
+    ///
+    /// let dumps = dumps.lock().unwrap();
+    /// assert_eq!(dumps[0].pointer, 1);
+    /// assert_eq!(&dumps[0].cells[..3], &[3, 2, 0]);
+    /// ```
+    pub fn on_debug<F: FnMut(&Dump) + Send + 'static>(self, f: F) -> Exec {
+        Exec {
+            on_debug: Some(Box::new(f)),
+            ..self.debug(true)
+        }
+    }
+
+    /// Records every instruction run to the file at `path`, overwriting it.
+    ///
+    /// Each line holds six numbers separated by a space: the start and end byte offsets of the
+    /// instruction in the source, then the pointer and the value of the cell it points to, before
+    /// and after running it. The interpreter and the transpiler produce the same trace, and a
+    /// [`Debugger`] records the steps it runs.
+    ///
+    /// [`Debugger`]: struct.Debugger.html
+    /// ```no_run
+    /// # use bf_lib::Exec;
+    /// Exec::prog("+>++")
+    ///     .trace(Some("trace.txt".into()))
+    ///     .interpret()
+    ///     .unwrap();
+    ///
+    /// let trace = std::fs::read_to_string("trace.txt").unwrap();
+    /// assert_eq!(trace, "0 1 0 0 0 1\n1 2 0 1 1 0\n2 4 1 0 1 2\n");
+    /// ```
+    pub fn trace(self, trace: Option<PathBuf>) -> Exec {
+        Exec { trace, ..self }
+    }
+
+    /// Saves a [`Snapshot`] to `path` if the interpreter is stopped by the timeout, the step
+    /// limit or the cancellation token, so the run can be continued with [`resume`].
+    ///
+    /// [`run`] uses the interpreter when a checkpoint is set, [`transpile`] returns an
+    /// [`Error::Unsupported`].
+    ///
+    /// [`Snapshot`]: struct.Snapshot.html
+    /// [`resume`]: struct.Exec.html#method.resume
+    /// [`run`]: struct.Exec.html#method.run
+    /// [`transpile`]: struct.Exec.html#method.transpile
+    /// [`Error::Unsupported`]: enum.Error.html#variant.Unsupported
+    pub fn checkpoint(self, checkpoint: Option<PathBuf>) -> Exec {
+        Exec { checkpoint, ..self }
+    }
+
     /// Sets the temporary file path for the transpiler.
     pub fn tmpdir(self, tmp_path: Option<PathBuf>) -> Exec {
         Exec {
@@ -122,24 +604,69 @@ impl Exec {
     /// [`transpile`]: struct.Exec.html#method.interpret
     /// [`interpret`]: struct.Exec.html#method.transpile
     pub fn run(self) -> Result<String, Error> {
-        bf::run(&self.program, self.input, self.time, self.tmp_path)
+        bf::run(self)
     }
     
     /// Runs the program with the interpreter, returning the output or an [`Error`].
     pub fn interpret(self) -> Result<String, Error> {
-        bf::interpreter::run(&self.program, self.input, self.time)
+        bf::interpreter::run(self)
     }
     
     /// Runs the program with the transpiler, returning the output or an [`Error`].
     ///
     /// Needs read and write permission in the chosen temporary file folder.
     pub fn transpile(self) -> Result<String, Error> {
-        bf::transpiler::run(&self.program, self.input, self.time, self.tmp_path)
+        bf::transpiler::run(self)
+    }
+
+    /// Async version of [`run`], available with the `async` feature.
+    ///
+    /// Needs to be called from a tokio runtime with the time and process drivers enabled.
+    ///
+    /// [`run`]: struct.Exec.html#method.run
+    #[cfg(feature = "async")]
+    pub async fn run_async(self) -> Result<String, Error> {
+        bf::run_async(self).await
+    }
+
+    /// Async version of [`interpret`], yields to the runtime every few thousand instructions.
+    ///
+    /// [`interpret`]: struct.Exec.html#method.interpret
+    #[cfg(feature = "async")]
+    pub async fn interpret_async(self) -> Result<String, Error> {
+        bf::interpreter::run_async(self).await
+    }
+
+    /// Async version of [`transpile`], rustc and the executable are awaited without blocking.
+    ///
+    /// [`transpile`]: struct.Exec.html#method.transpile
+    #[cfg(feature = "async")]
+    pub async fn transpile_async(self) -> Result<String, Error> {
+        bf::transpiler::run_async(self).await
+    }
+
+    /// Runs the program with the interpreter, counting how many times each instruction and loop
+    /// ran and how long each loop took.
+    ///
+    /// See [`Profile`] for the results.
+    ///
+    /// [`Profile`]: struct.Profile.html
+    pub fn profile(self) -> Result<Profile, Error> {
+        bf::interpreter::profile(self)
+    }
+
+    /// Prepares the program to be run one step at a time with the interpreter.
+    ///
+    /// Timeout, step limit and cancellation are ignored, the [`Debugger`] only runs when asked.
+    ///
+    /// [`Debugger`]: struct.Debugger.html
+    pub fn debugger(self) -> Result<Debugger, Error> {
+        bf::interpreter::debugger(self)
     }
 
     /// Translated the program to rust code
     pub fn translate(&self) -> Result<String, Error> {
-        bf::transpiler::translate(&self.program, self.input.clone())
+        bf::transpiler::translate(self)
     }
 }
 
@@ -151,6 +678,7 @@ impl Exec {
 /// bf_lib::check_brackets(ok).unwrap();
 /// bf_lib::check_brackets(err).unwrap_err();
 /// ```
+#[allow(clippy::redundant_pattern_matching, clippy::len_zero)]
 pub fn check_brackets(prog: &str) -> Result<(), Error> {
     let mut open: Vec<usize> = Vec::new();
     for (i, b) in prog.as_bytes().iter().enumerate() {
@@ -158,14 +686,14 @@ pub fn check_brackets(prog: &str) -> Result<(), Error> {
             b'[' => open.push(i),
             b']' => {
                 if let None = open.pop() {
-                    return Err(Error::Syntax(i));
+                    return Err(Error::Syntax(Position::new(prog, i)));
                 };
             }
             _ => (),
         }
     }
     if open.len() != 0 {
-        Err(Error::Syntax(open.pop().unwrap()))
+        Err(Error::Syntax(Position::new(prog, open.pop().unwrap())))
     } else {
         Ok(())
     }
@@ -173,18 +701,49 @@ pub fn check_brackets(prog: &str) -> Result<(), Error> {
 
 /// Checks if the program will try to read user input.
 ///
+/// Reads in code that never runs are ignored, see [`input_demand`].
+///
+/// [`input_demand`]: fn.input_demand.html
 /// ```
 /// let reads = ",[>+>+<<-]>.>.";
 /// let does_not_read = "foo. bar.";
+/// let comment = "[ a comment, never run ] foo. bar.";
 ///
 /// assert_eq!(true, bf_lib::wants_input(reads));
 /// assert_eq!(false, bf_lib::wants_input(does_not_read));
+/// assert_eq!(false, bf_lib::wants_input(comment));
 /// ```
 pub fn wants_input(program: &str) -> bool {
-    program.contains(",")
+    input_demand(program).map_or(program.contains(','), |d| d.max != Some(0))
 }
 
+mod analysis;
+pub use analysis::{input_demand, memory_bounds, InputDemand, MemoryBounds};
+
+pub mod asm;
+
 mod bf;
 
+mod debugger;
+pub use debugger::{Debugger, Event, Watch};
+
+mod dialect;
+pub use dialect::{Dialect, Substitution};
+
+mod diagnostic;
+pub use diagnostic::{validate, Diagnostic, Lint};
+
+mod format;
+pub use format::{fmt, minify, Style};
+
+pub mod gen;
+
+mod profile;
+pub use profile::{LoopStats, Profile};
+
+mod snapshot;
+pub use snapshot::Snapshot;
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests;
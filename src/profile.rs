@@ -0,0 +1,157 @@
+use crate::{bf::Instruction, Lines, Span};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Execution statistics of a loop, from its `[` to its `]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Source of the whole loop, brackets included.
+    pub span: Span,
+    /// How many times the loop was reached.
+    pub entries: u64,
+    /// How many times the body was run.
+    pub iterations: u64,
+    /// Time spent inside the loop, nested loops included.
+    pub time: Duration,
+}
+
+/// Result of [`Exec::profile`]: how many times each part of the program ran.
+///
+/// Its [`Display`] implementation prints the [`report`].
+///
+/// [`Exec::profile`]: struct.Exec.html#method.profile
+/// [`Display`]: https://doc.rust-lang.org/std/fmt/trait.Display.html
+/// [`report`]: struct.Profile.html#method.report
+/// ```
+/// # use bf_lib::Exec;
+/// let profile = Exec::prog("+++[>++<-]>.").profile().unwrap();
+///
+/// assert_eq!(profile.output, "\u{6}");
+/// assert_eq!(profile.loops[0].entries, 1);
+/// assert_eq!(profile.loops[0].iterations, 3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// What the program printed.
+    pub output: String,
+    /// How many times each instruction ran, along with the source it was parsed from.
+    ///
+    /// Runs of `+-` or `<>` are a single instruction.
+    pub instructions: Vec<(Span, u64)>,
+    /// Statistics of every loop, in source order.
+    pub loops: Vec<LoopStats>,
+    source: String,
+}
+
+impl Profile {
+    /// Renders the profile as text: the source annotated with how many instructions ran on each
+    /// line, followed by the loops that took the most time.
+    pub fn report(&self) -> String {
+        let lines = Lines::new(&self.source);
+        let mut per_line = vec![0u64; self.source.lines().count().max(1)];
+        for (span, count) in &self.instructions {
+            per_line[lines.line_of(span.start)] += count;
+        }
+        let width = per_line.iter().max().unwrap_or(&0).to_string().len().max(5);
+        let mut out = format!("{:>w$} | source\n", "count", w = width);
+        for (line, count) in self.source.lines().zip(&per_line) {
+            out.push_str(&format!("{:>w$} | {}\n", count, line, w = width));
+        }
+
+        let mut loops: Vec<&LoopStats> = self.loops.iter().collect();
+        loops.sort_by_key(|l| std::cmp::Reverse(l.time));
+        out.push_str("\nloops, slowest first:\n");
+        out.push_str("        time      entries   iterations  source\n");
+        for l in loops {
+            let text: String = self.source[l.span.start..l.span.end]
+                .lines()
+                .next()
+                .unwrap_or("")
+                .chars()
+                .take(40)
+                .collect();
+            out.push_str(&format!(
+                "{:>12.3?} {:>12} {:>12}  line {}: {}\n",
+                l.time,
+                l.entries,
+                l.iterations,
+                lines.line_of(l.span.start) + 1,
+                text
+            ));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.report())
+    }
+}
+
+/// Counters updated by the interpreter while profiling.
+pub(crate) struct Counters {
+    counts: Vec<u64>,
+    /// Time spent in the loop starting at each instruction
+    time: Vec<Duration>,
+    /// When each of the loops currently running was entered
+    entered: Vec<Instant>,
+}
+
+impl Counters {
+    pub(crate) fn new(len: usize) -> Counters {
+        Counters {
+            counts: vec![0; len],
+            time: vec![Duration::default(); len],
+            entered: Vec::new(),
+        }
+    }
+
+    /// Records that the instruction at `pc` ran, `jumped` tells if it changed the instruction
+    /// pointer.
+    pub(crate) fn record(&mut self, pc: usize, inst: Instruction, jumped: bool) {
+        self.counts[pc] += 1;
+        match inst {
+            Instruction::LoopStart(_) if !jumped => self.entered.push(Instant::now()),
+            Instruction::LoopEnd(start) if !jumped => {
+                if let Some(t) = self.entered.pop() {
+                    self.time[start] += t.elapsed()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub(crate) fn into_profile(
+        self,
+        insts: &[Instruction],
+        spans: &[Span],
+        source: String,
+        output: String,
+    ) -> Profile {
+        let loops = insts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, inst)| match inst {
+                Instruction::LoopStart(end) => Some(LoopStats {
+                    span: Span {
+                        start: spans[i].start,
+                        end: spans[*end].end,
+                    },
+                    entries: self.counts[i],
+                    iterations: self.counts[*end],
+                    time: self.time[i],
+                }),
+                _ => None,
+            })
+            .collect();
+        Profile {
+            output,
+            instructions: spans.iter().copied().zip(self.counts).collect(),
+            loops,
+            source,
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    bf::{interpreter::Thread, Extensions},
+    Error,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"bfsnap1\n";
+
+/// Saved state of a program run by the interpreter.
+///
+/// Snapshots are written when a run with [`Exec::checkpoint`] is stopped by a timeout, a step
+/// limit or a [`CancelToken`], or taken from a [`Debugger`]. [`Exec::resume`] continues the run
+/// from exactly where it stopped.
+///
+/// [`Exec::checkpoint`]: struct.Exec.html#method.checkpoint
+/// [`CancelToken`]: struct.CancelToken.html
+/// [`Debugger`]: struct.Debugger.html
+/// [`Exec::resume`]: struct.Exec.html#method.resume
+/// ```
+/// # use bf_lib::{Error, Exec, Snapshot};
+/// let path = std::env::temp_dir().join("bf-lib-doc.snap");
+/// let res = Exec::prog("+++[>++<-]>.")
+///     .max_steps(5)
+///     .checkpoint(Some(path.clone()))
+///     .interpret();
+/// assert!(matches!(res, Err(Error::StepLimitExceeded)));
+///
+/// let snapshot = Snapshot::load(&path).unwrap();
+/// assert_eq!(snapshot.steps(), 5);
+/// assert_eq!(Exec::resume(snapshot).interpret().unwrap(), "\u{6}");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub(crate) source: String,
+    pub(crate) ext: Extensions,
+    /// The tape without the trailing zeroes
+    pub(crate) mem: Vec<u8>,
+    pub(crate) pointer: usize,
+    pub(crate) pc: usize,
+    pub(crate) input: Vec<u8>,
+    pub(crate) cursor: usize,
+    pub(crate) output: String,
+    pub(crate) steps: u64,
+    /// Start of every defined procedure, by name
+    pub(crate) procs: Vec<(u8, usize)>,
+    /// Return addresses of the running procedures
+    pub(crate) calls: Vec<usize>,
+    /// Every thread once the program has forked
+    pub(crate) threads: Vec<Thread>,
+    /// Index of the running thread
+    pub(crate) thread: usize,
+}
+
+impl Snapshot {
+    /// Source of the program, translated to brainfuck if it was written in another [`Dialect`].
+    ///
+    /// [`Dialect`]: enum.Dialect.html
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Output printed before the snapshot was taken.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// Number of instructions run before the snapshot was taken.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Writes the snapshot to a file, overwriting it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut w = BufWriter::new(File::create(path).map_err(Error::Io)?);
+        self.write(&mut w)
+            .and_then(|_| w.flush())
+            .map_err(Error::Io)
+    }
+
+    /// Reads a snapshot written by [`save`].
+    ///
+    /// [`save`]: struct.Snapshot.html#method.save
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot, Error> {
+        let mut r = BufReader::new(File::open(path).map_err(Error::Io)?);
+        Snapshot::read(&mut r).map_err(Error::Io)
+    }
+
+    /// Serializes the snapshot in a compact binary format.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        write_bytes(w, self.source.as_bytes())?;
+        let flags = self.ext.debug as u8
+            | (self.ext.procedures as u8) << 1
+            | (self.ext.fork as u8) << 2;
+        w.write_all(&[flags])?;
+        write_bytes(w, &self.mem)?;
+        write_u64(w, self.pointer as u64)?;
+        write_u64(w, self.pc as u64)?;
+        write_bytes(w, &self.input)?;
+        write_u64(w, self.cursor as u64)?;
+        write_bytes(w, self.output.as_bytes())?;
+        write_u64(w, self.steps)?;
+        // left out without the extensions, so older snapshots can still be read
+        if self.ext.procedures {
+            write_u64(w, self.procs.len() as u64)?;
+            for &(name, start) in &self.procs {
+                w.write_all(&[name])?;
+                write_u64(w, start as u64)?;
+            }
+            write_calls(w, &self.calls)?;
+        }
+        if self.ext.fork {
+            write_u64(w, self.thread as u64)?;
+            write_u64(w, self.threads.len() as u64)?;
+            for t in &self.threads {
+                write_u64(w, t.pc as u64)?;
+                write_u64(w, t.pointer as u64)?;
+                write_calls(w, &t.calls)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot serialized by [`write`].
+    ///
+    /// [`write`]: struct.Snapshot.html#method.write
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a bf-lib snapshot"));
+        }
+        let source = String::from_utf8(read_bytes(r)?).map_err(|_| invalid("invalid source"))?;
+        let mut flags = [0];
+        r.read_exact(&mut flags)?;
+        let ext = Extensions {
+            debug: flags[0] & 1 != 0,
+            procedures: flags[0] & 2 != 0,
+            fork: flags[0] & 4 != 0,
+        };
+        let mem = read_bytes(r)?;
+        let pointer = read_u64(r)? as usize;
+        let pc = read_u64(r)? as usize;
+        let input = read_bytes(r)?;
+        let cursor = read_u64(r)? as usize;
+        let output = String::from_utf8(read_bytes(r)?).map_err(|_| invalid("invalid output"))?;
+        let steps = read_u64(r)?;
+        let (mut procs, mut calls) = (Vec::new(), Vec::new());
+        if ext.procedures {
+            for _ in 0..read_u64(r)? {
+                let mut name = [0];
+                r.read_exact(&mut name)?;
+                procs.push((name[0], read_u64(r)? as usize));
+            }
+            calls = read_calls(r)?;
+        }
+        let (mut thread, mut threads) = (0, Vec::new());
+        if ext.fork {
+            thread = read_u64(r)? as usize;
+            for _ in 0..read_u64(r)? {
+                threads.push(Thread {
+                    pc: read_u64(r)? as usize,
+                    pointer: read_u64(r)? as usize,
+                    calls: read_calls(r)?,
+                });
+            }
+        }
+        Ok(Snapshot {
+            source,
+            ext,
+            mem,
+            pointer,
+            pc,
+            input,
+            cursor,
+            output,
+            steps,
+            procs,
+            calls,
+            threads,
+            thread,
+        })
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn write_bytes<W: Write>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    write_u64(w, b.len() as u64)?;
+    w.write_all(b)
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_calls<W: Write>(w: &mut W, calls: &[usize]) -> io::Result<()> {
+    write_u64(w, calls.len() as u64)?;
+    calls.iter().try_for_each(|&ret| write_u64(w, ret as u64))
+}
+
+fn read_calls<R: Read>(r: &mut R) -> io::Result<Vec<usize>> {
+    (0..read_u64(r)?).map(|_| Ok(read_u64(r)? as usize)).collect()
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u64(r)?;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
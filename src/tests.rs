@@ -1,13 +1,56 @@
 use super::*;
+
+/// Procedure 1 prints the counter in the next cell and calls itself until it's zero.
+const COUNTDOWN: &str = "+(>.-[<:>]<)>+++<:";
+/// Both threads move to cell 2, then take turns adding to it and printing it.
+const RACE: &str = "Y[<]>>+.+.+.";
+/// Output of `RACE` with the deterministic scheduler.
+const RACE_OUTPUT: &str = "\u{1}\u{3}\u{3}\u{5}\u{5}\u{6}";
+
 #[test]
 fn input_check() {
     assert_eq!(wants_input("foo , bar"), true);
     assert_eq!(wants_input("foo . bar"), false);
 }
 
+#[test]
+fn input_analysis() {
+    let demand = |p| input_demand(p).unwrap();
+    assert_eq!(demand(",>,"), InputDemand { min: 2, max: Some(2) });
+    assert_eq!(demand("[,]>+++[-]+[,]"), InputDemand { min: 1, max: None });
+    assert_eq!(demand("+[[-]>,[-]]"), InputDemand { min: 1, max: Some(1) });
+    assert_eq!(demand("+[]>,"), InputDemand { min: 1, max: Some(1) });
+    assert_eq!(demand("<,"), InputDemand { min: 0, max: Some(0) });
+    assert!(input_demand("[,").is_err());
+
+    // fails before printing anything
+    let pos = Position { offset: 12, line: 2, column: 3 };
+    for res in [
+        Exec::prog("++[.>,<-]\n.>,").input(Some(String::from("ab"))).interpret(),
+        Exec::prog("++[.>,<-]\n.>,").input(Some(String::from("ab"))).transpile(),
+    ] {
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::InputTooShort(p))) if p == pos));
+    }
+    // unless it never gets there
+    let time = Some(std::time::Duration::from_millis(200));
+    for res in [
+        Exec::prog("+[],").timeout(time).interpret(),
+        Exec::prog("+[],").timeout(time).transpile(),
+    ] {
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+    assert_eq!(demand("+[],"), InputDemand { min: 1, max: Some(1) });
+}
+
 #[cfg(test)]
 mod transpiler {
-    use crate::Exec;
+    use super::COUNTDOWN;
+    use crate::{CancelToken, Error, Exec, Position, RuntimeError};
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
     #[test]
     fn in_out() {
         assert_eq!(
@@ -38,11 +81,121 @@ mod transpiler {
     fn out_of_input() {
         Exec::prog(",").transpile().unwrap();
     }
+
+    #[test]
+    fn error_position() {
+        let res = Exec::prog("+[-]\n  <<+").transpile();
+        let pos = Position { offset: 7, line: 2, column: 3 };
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(p))) if p == pos));
+        let res = Exec::prog(".,\n,").input(Some(String::from("a"))).transpile();
+        let pos = Position { offset: 3, line: 2, column: 1 };
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::InputTooShort(p))) if p == pos));
+    }
+
+    #[test]
+    fn step_limit() {
+        assert!(Exec::prog("++[-].").max_steps(7).transpile().is_ok());
+        let res = Exec::prog("++[-].").max_steps(6).transpile();
+        assert!(matches!(res, Err(Error::StepLimitExceeded)));
+        let res = Exec::prog("+[]").max_steps(1000).transpile();
+        assert!(matches!(res, Err(Error::StepLimitExceeded)));
+    }
+
+    #[test]
+    fn cancel() {
+        let token = CancelToken::new();
+        let handle = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            handle.cancel();
+        });
+        let res = Exec::prog("+[]").cancel(token).transpile();
+        assert!(matches!(res, Err(Error::Cancelled)));
+
+        // nothing is compiled
+        let token = CancelToken::new();
+        token.cancel();
+        let res = Exec::prog("+.").cancel(token).transpile();
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn large_output() {
+        // prints 110000 times, more than a pipe can hold
+        let prog = "+".repeat(65)
+            + ">"
+            + &"+".repeat(100)
+            + "[>"
+            + &"+".repeat(100)
+            + "[>"
+            + &"+".repeat(11)
+            + "[<<<.>>>-]<-]<-]";
+        let res = Exec::prog(&prog)
+            .cancel(CancelToken::new())
+            .timeout(Some(Duration::from_secs(30)))
+            .transpile();
+        assert_eq!(res.unwrap(), "A".repeat(110000));
+    }
+
+    #[test]
+    fn procedures() {
+        let exec = |prog| Exec::prog(prog).procedures(true);
+        assert_eq!(exec(COUNTDOWN).transpile().unwrap(), "\u{3}\u{2}\u{1}");
+        assert_eq!(exec(COUNTDOWN).max_steps(100).transpile().unwrap(), "\u{3}\u{2}\u{1}");
+        let undefined = Position { offset: 5, line: 1, column: 6 };
+        assert!(matches!(
+            exec("+(.)+:").transpile(),
+            Err(Error::Runtime(RuntimeError::UndefinedProcedure(p))) if p == undefined
+        ));
+        let overflow = Position { offset: 2, line: 1, column: 3 };
+        assert!(matches!(
+            exec("+(:):").transpile(),
+            Err(Error::Runtime(RuntimeError::StackOverflow(p))) if p == overflow
+        ));
+    }
+
+    #[test]
+    fn fork() {
+        let res = Exec::prog("Y").fork(true).transpile();
+        assert!(matches!(res, Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn debug_dump() {
+        let dumps = Arc::new(Mutex::new(Vec::new()));
+        let sink = dumps.clone();
+        Exec::prog("+++>++# >>>>>>-#")
+            .on_debug(move |d| sink.lock().unwrap().push(d.to_string()))
+            .transpile()
+            .unwrap();
+        assert_eq!(
+            *dumps.lock().unwrap(),
+            vec![
+                "#6: pointer 1, cells 0..6: 3 2 0 0 0 0",
+                "#15: pointer 7, cells 3..12: 0 0 0 0 255 0 0 0 0",
+            ]
+        );
+
+        // dumped before the program was stopped
+        let count = Arc::new(Mutex::new(0));
+        let sink = count.clone();
+        let res = Exec::prog("+#[]")
+            .on_debug(move |_| *sink.lock().unwrap() += 1)
+            .timeout(Some(Duration::from_millis(300)))
+            .transpile();
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
 }
 
 #[cfg(test)]
 mod interpreter {
-    use crate::Exec;
+    use super::{COUNTDOWN, RACE, RACE_OUTPUT};
+    use crate::{Error, Exec, Position, RuntimeError};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
     #[test]
     fn in_out() {
         assert_eq!(
@@ -62,6 +215,58 @@ mod interpreter {
         );
     }
 
+    #[test]
+    fn nested_loops() {
+        assert_eq!(
+            Exec::prog("[-]++++[>+++[>++++<-]<-]>>+.[[-]]")
+                .interpret()
+                .unwrap(),
+            String::from("1")
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        let res = Exec::prog("+[]")
+            .timeout(Some(Duration::from_millis(100)))
+            .interpret();
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn step_limit() {
+        assert!(Exec::prog("++[-].").max_steps(7).interpret().is_ok());
+        let res = Exec::prog("++[-].").max_steps(6).interpret();
+        assert!(matches!(res, Err(Error::StepLimitExceeded)));
+        let res = Exec::prog("+[]").max_steps(1000).interpret();
+        assert!(matches!(res, Err(Error::StepLimitExceeded)));
+    }
+
+    #[test]
+    fn debug_dump() {
+        let dumps = Arc::new(Mutex::new(Vec::new()));
+        let sink = dumps.clone();
+        Exec::prog("+++>++# >>>>>>-#")
+            .on_debug(move |d| sink.lock().unwrap().push(d.to_string()))
+            .interpret()
+            .unwrap();
+        assert_eq!(
+            *dumps.lock().unwrap(),
+            vec![
+                "#6: pointer 1, cells 0..6: 3 2 0 0 0 0",
+                "#15: pointer 7, cells 3..12: 0 0 0 0 255 0 0 0 0",
+            ]
+        );
+        // without the extension `#` is a comment
+        assert_eq!(Exec::prog("+#.").interpret().unwrap(), "\u{1}");
+    }
+
+    #[test]
+    #[should_panic]
+    fn unmatched_bracket() {
+        Exec::prog("+[[-]").interpret().unwrap();
+    }
+
     #[test]
     #[should_panic]
     fn out_of_bounds() {
@@ -73,4 +278,749 @@ mod interpreter {
     fn out_of_input() {
         Exec::prog(",").interpret().unwrap();
     }
+
+    #[test]
+    fn error_position() {
+        let res = Exec::prog("+[-]\n  <<+").interpret();
+        let pos = Position { offset: 7, line: 2, column: 3 };
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(p))) if p == pos));
+        let res = Exec::prog(".,\n,").input(Some(String::from("a"))).interpret();
+        let pos = Position { offset: 3, line: 2, column: 1 };
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::InputTooShort(p))) if p == pos));
+        let res = Exec::prog("ü[\n[-]").interpret();
+        let pos = Position { offset: 2, line: 1, column: 2 };
+        assert!(matches!(res, Err(Error::Syntax(p)) if p == pos));
+    }
+
+    #[test]
+    fn procedures() {
+        let exec = |prog| Exec::prog(prog).procedures(true);
+        assert_eq!(exec(COUNTDOWN).interpret().unwrap(), "\u{3}\u{2}\u{1}");
+        // without the extension they're comments
+        assert_eq!(Exec::prog("+(.)").interpret().unwrap(), "\u{1}");
+        let undefined = Position { offset: 5, line: 1, column: 6 };
+        assert!(matches!(
+            exec("+(.)+:").interpret(),
+            Err(Error::Runtime(RuntimeError::UndefinedProcedure(p))) if p == undefined
+        ));
+        let overflow = Position { offset: 2, line: 1, column: 3 };
+        assert!(matches!(
+            exec("+(:):").interpret(),
+            Err(Error::Runtime(RuntimeError::StackOverflow(p))) if p == overflow
+        ));
+        for prog in ["([)]", "(]", "+)"].iter() {
+            assert!(matches!(exec(prog).interpret(), Err(Error::Syntax(_))));
+        }
+    }
+
+    #[test]
+    fn fork() {
+        let exec = |prog: &str| Exec::prog(prog).fork(true);
+        assert_eq!(exec("Y>+++.").interpret().unwrap(), "\u{3}\u{4}");
+        assert_eq!(exec(RACE).interpret().unwrap(), RACE_OUTPUT);
+        assert_eq!(exec(RACE).run().unwrap(), RACE_OUTPUT);
+        // without the extension it's a comment
+        assert_eq!(Exec::prog("Y>+++.").interpret().unwrap(), "\u{3}");
+        // every thread forks a child until there are too many
+        let many = Position { offset: 2, line: 1, column: 3 };
+        assert!(matches!(
+            exec("+[Y]").interpret(),
+            Err(Error::Runtime(RuntimeError::TooManyThreads(p))) if p == many
+        ));
+        let res = exec(&(">".repeat(29999) + "Y")).interpret();
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(_)))));
+    }
+
+    #[test]
+    fn real_threads() {
+        let exec = |prog: &str| Exec::prog(prog).fork(true).real_threads(true);
+        let mut output: Vec<char> = exec("Y>+++.").interpret().unwrap().chars().collect();
+        output.sort_unstable();
+        assert_eq!(output, ['\u{3}', '\u{4}']);
+        // additions can be lost, prints can't
+        let output = exec(RACE).interpret().unwrap();
+        assert_eq!(output.len(), 6);
+        assert!(output.chars().all(|c| ('\u{1}'..='\u{6}').contains(&c)));
+
+        let many = Position { offset: 2, line: 1, column: 3 };
+        assert!(matches!(
+            exec("+[Y]").interpret(),
+            Err(Error::Runtime(RuntimeError::TooManyThreads(p))) if p == many
+        ));
+        let res = exec(&(">".repeat(29999) + "Y")).interpret();
+        assert!(matches!(res, Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(_)))));
+        let res = exec("+[Y]").max_steps(100).interpret();
+        assert!(matches!(res, Err(Error::StepLimitExceeded)));
+        // a panic in a thread reaches the caller
+        let res = std::panic::catch_unwind(|| {
+            exec("Y#")
+                .timeout(Some(Duration::from_millis(200)))
+                .on_debug(|_| panic!("in a callback"))
+                .interpret()
+        });
+        assert!(res.is_err());
+    }
+}
+
+#[test]
+fn diagnostic() {
+    let source = "+[\n\t>[-]\n\n.";
+    let err = Exec::prog(source).interpret().unwrap_err();
+    assert_eq!(
+        err.render(source),
+        "error: unmatched bracket
+ --> 1:2
+  |
+1 | +[
+  |  ^ this `[` is never closed
+...
+4 | .
+  |  - expected `]` before the end
+"
+    );
+    let source = "+\n\t<";
+    let err = Exec::prog(source).interpret().unwrap_err();
+    assert_eq!(
+        err.render(source),
+        "error: memory access out of bounds
+ --> 2:2
+  |
+2 | \t<
+  | \t^ the pointer leaves the tape here
+"
+    );
+    let err = Exec::prog("+[]").max_steps(5).interpret().unwrap_err();
+    assert_eq!(err.render("+[]"), "error: Step limit exceeded.\n");
+    assert_eq!(err.to_string(), "Error, I didn't quite get that.\nStep limit exceeded.");
+}
+
+#[test]
+fn memory_analysis() {
+    let bounds = |p| memory_bounds(p).unwrap();
+    assert_eq!(bounds("++[>+[>+<-]<-]"), MemoryBounds { extent: Some(3), underflow: None });
+    assert_eq!(bounds("+[>+]").extent, None);
+    assert_eq!(bounds("+[>[<]>]").extent, None);
+    let underflow = bounds(">[>]<<<<").underflow.unwrap();
+    assert_eq!((underflow.offset, underflow.column), (4, 5));
+    let big = ">".repeat(30000);
+    assert_eq!(bounds(&big).extent, Some(30001));
+    assert!(matches!(
+        Exec::prog(&big).interpret(),
+        Err(Error::Runtime(RuntimeError::OutOfMemoryBounds(_)))
+    ));
+
+    let found = validate("+[<]>.<<.");
+    assert_eq!(found.len(), 1);
+    assert_eq!((found[0].lint, found[0].span), (Lint::Underflow, Span { start: 2, end: 3 }));
+    assert!(validate("+>[>]<").is_empty());
+}
+
+#[test]
+fn format() {
+    let prog = "Hello\n++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.\n# end";
+    let style = Style { width: 20, ..Style::default() };
+    let pretty = fmt(prog, &style).unwrap();
+    assert_eq!(
+        pretty,
+        "Hello
+++++++++ [
+    > ++++ [
+        > ++ > +++ >
+        +++ > + <<<<
+        -
+    ]
+    > + > + > - >> +
+    [<] < -
+]
+>> . > --- . #
+end
+"
+    );
+    assert_eq!(fmt(&pretty, &style).unwrap(), pretty);
+    let stripped = fmt(prog, &Style { comments: false, ..style }).unwrap();
+    assert!(!stripped.contains("Hello") && stripped.ends_with(". #\n"));
+    let run = |p: &str| Exec::prog(p).interpret().unwrap();
+    assert_eq!(run(&pretty), run(prog));
+    assert!(fmt("[[]", &Style::default()).is_err());
+
+    let style = Style {
+        comments: false,
+        procedures: true,
+        fork: true,
+        ..Style::default()
+    };
+    assert_eq!(fmt("+(>.):", &style).unwrap(), "+ (> .) :\n");
+    assert_eq!(fmt("Y[<]>>+.", &style).unwrap(), "Y [<] >> + .\n");
+    let pretty = fmt(COUNTDOWN, &Style { width: 12, ..style }).unwrap();
+    assert_eq!(pretty, "+ (\n    > . -\n    [< : >]\n    <\n)\n> +++ < :\n");
+    let run = |p: &str| Exec::prog(p).procedures(true).interpret().unwrap();
+    assert_eq!(run(&pretty), run(COUNTDOWN));
+    assert!(matches!(fmt("([)]", &style), Err(Error::Syntax(_))));
+}
+
+#[test]
+fn minifier() {
+    let prog = "[ comment, with commands. ] Hello\n\
+        ++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.\
+        +++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let small = minify(prog).unwrap();
+    assert_eq!(small.len(), 106);
+    assert_eq!(Exec::prog(&small).interpret().unwrap(), "Hello World!\n");
+    assert_eq!(minify(&small).unwrap(), small);
+    assert_eq!(minify(&"+".repeat(250)).unwrap(), "");
+    assert_eq!(minify(&format!("{}.", "+".repeat(250))).unwrap(), "------.");
+    assert_eq!(minify(">+<-+>-,[]").unwrap(), ">,[]");
+    assert_eq!(minify("+>[-]<[-][-]<.").unwrap(), "+>[-]<[-]<.");
+}
+
+#[test]
+fn generator() {
+    let all: Vec<u8> = (0..=255).rev().collect();
+    let texts: [&[u8]; 4] = [b"", b"\n\n\n", b"Hello, World!\nbrainfuck 123 ~", &all];
+    for text in texts.iter() {
+        let prog = gen::print_string(text);
+        let out = Exec::prog(&prog).interpret().unwrap();
+        assert_eq!(out, text.iter().map(|&b| b as char).collect::<String>());
+    }
+    assert_eq!(gen::print_string(b""), "");
+    assert!(gen::print_string(&[b'a'; 100]).len() < 150);
+}
+
+#[test]
+fn assembler() {
+    let prog = asm::compile(
+        "cell n copy   # a digit and its copy
+        read n
+        sub n 48
+        while n {
+            print \"*\"
+            if n { move n copy }
+            move copy n
+            sub n 1
+            if n { print \", \" }
+        }
+        print \"\\\"done\\\"\\n\"",
+    )
+    .unwrap();
+    let run = |input: &str| Exec::prog(&prog).input(Some(input.to_owned())).interpret().unwrap();
+    assert_eq!(run("3"), "*, *, *\"done\"\n");
+    assert_eq!(run("0"), "\"done\"\n");
+
+    let errors = [
+        ("cell a\nadd b 1", "unknown cell `b`", 2, 5),
+        ("cell a\nwhile a {\n add a 1", "expected `}`", 3, 9),
+        ("cell a\nadd a x", "`x` isn't a number", 2, 7),
+        ("cell a\nprint \"\\q\"", "unknown escape", 2, 9),
+        ("cell a a", "cell `a` is already declared", 1, 8),
+        ("jump", "unknown statement `jump`", 1, 1),
+    ];
+    for (src, msg, line, column) in errors.iter() {
+        match asm::compile(src) {
+            Err(Error::Asm(p, m)) => {
+                assert_eq!((m.as_str(), p.line, p.column), (*msg, *line, *column))
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+    let err = asm::compile("cell a\nadd b 1").unwrap_err();
+    assert!(err.render("cell a\nadd b 1").starts_with("error: unknown cell `b`\n --> 2:5\n"));
+}
+
+#[test]
+fn ook() {
+    let bf = "++++++++[>++++++++<-]>+.+.,.";
+    let ook = Dialect::Ook.from_brainfuck(bf);
+    assert_eq!(ook.lines().count(), 4);
+    assert_eq!(Dialect::Ook.to_brainfuck(&ook).unwrap(), bf);
+    let exec = || Exec::prog(&ook).dialect(Dialect::Ook).input(Some("z".to_owned()));
+    assert_eq!(exec().interpret().unwrap(), "ABz");
+    assert_eq!(exec().transpile().unwrap(), "ABz");
+
+    let prog = "Ook. Ook.\nOok!  Ook. comment Ook. Ook? Ook! Ook?";
+    match Exec::prog(prog).dialect(Dialect::Ook).interpret() {
+        Err(Error::Syntax(p)) => assert_eq!((p.line, p.column), (2, 30)),
+        res => panic!("{:?}", res),
+    }
+    match Exec::prog("Ook. Ook?\nOok? Ook?").dialect(Dialect::Ook).interpret() {
+        Err(Error::InvalidToken(p)) => assert_eq!((p.line, p.column), (2, 1)),
+        res => panic!("{:?}", res),
+    }
+    match Dialect::Ook.to_brainfuck("Ook. Ook? Ook!") {
+        Err(Error::InvalidToken(p)) => assert_eq!(p.column, 11),
+        res => panic!("{:?}", res),
+    }
+    let prog = "Ook. Ook! Ook? Ook?";
+    let err = Exec::prog(prog).dialect(Dialect::Ook).interpret().unwrap_err();
+    assert!(err.render(prog).starts_with("error: invalid token\n --> 1:11\n"));
+}
+
+#[test]
+fn substitution() {
+    let config = "; trivial substitution, with an alias
++ a
++ plus
+- b
+> c
+< d
+. e
+, f
+[ g
+] h
+# !";
+    let path = std::env::temp_dir().join("bf-lib-test.dialect");
+    std::fs::write(&path, config).unwrap();
+    let dialect = Dialect::Custom(Substitution::load(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    // `a` isn't read inside `plus`, `!` only counts with the extension
+    let prog = "plusaaa gcaaaadb hc e ü !";
+    let exec = || Exec::prog(prog).dialect(dialect.clone());
+    assert_eq!(exec().interpret().unwrap(), "\u{10}");
+    assert_eq!(exec().transpile().unwrap(), "\u{10}");
+    assert_eq!(dialect.to_brainfuck(prog).unwrap(), "++++[>++++<-]>.");
+    let dumps = std::sync::Arc::new(std::sync::Mutex::new(0));
+    let sink = dumps.clone();
+    exec().on_debug(move |_| *sink.lock().unwrap() += 1).interpret().unwrap();
+    assert_eq!(*dumps.lock().unwrap(), 1);
+    // snapshots are taken in brainfuck
+    let mut dbg = exec().debug(true).debugger().unwrap();
+    for _ in 0..5 {
+        dbg.step().unwrap();
+    }
+    assert_eq!(dbg.snapshot().source(), "++++[>++++<-]>.#");
+    assert_eq!(Exec::resume(dbg.snapshot()).interpret().unwrap(), "\u{10}");
+
+    let bf = "+[-]>,.";
+    let translated = dialect.from_brainfuck(bf);
+    assert_eq!(translated, "plus g b h c f e\n");
+    assert_eq!(dialect.to_brainfuck(&translated).unwrap(), bf);
+
+    match Exec::prog("ag\nb").dialect(dialect).interpret() {
+        Err(Error::Syntax(p)) => assert_eq!((p.line, p.column), (1, 2)),
+        res => panic!("{:?}", res),
+    }
+    for (config, (line, column)) in [("x y", (1, 1)), ("+ a\n  -", (2, 4))].iter() {
+        match Substitution::parse(config) {
+            Err(Error::Dialect(p, _)) => assert_eq!((p.line, p.column), (*line, *column)),
+            res => panic!("{:?}", res),
+        }
+    }
+}
+
+#[test]
+fn validation() {
+    let found: Vec<(Lint, usize, usize)> = validate("[[ wait ]]\n>a<+ - +]][")
+        .into_iter()
+        .map(|d| (d.lint, d.span.start, d.position.line))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (Lint::InfiniteLoop, 1, 1),
+            (Lint::Cancellation, 11, 2),
+            (Lint::Cancellation, 14, 2),
+            (Lint::UnopenedBracket, 19, 2),
+            (Lint::UnopenedBracket, 20, 2),
+            (Lint::UnclosedBracket, 21, 2),
+        ]
+    );
+    assert!(found.iter().filter(|f| f.0.is_error()).count() == 3);
+    assert!(validate("+[>[-]<-]").is_empty());
+    assert!(validate("").is_empty());
+
+    // long lines with characters of every width
+    let source = "é+-∞".repeat(30) + "\n" + &"+-🦀 ".repeat(40) + "\n][";
+    let found = validate(&source);
+    assert_eq!(found.len(), 72);
+    for d in found {
+        assert_eq!(d.position, Position::new(&source, d.span.start));
+    }
+}
+
+/// Traces the program with both backends, checks that they agree and returns the trace.
+fn trace_both(name: &str, exec: impl Fn() -> Exec) -> String {
+    let dir = std::env::temp_dir();
+    let int = dir.join(format!("bf-lib-trace-{}-int", name));
+    let tra = dir.join(format!("bf-lib-trace-{}-tra", name));
+    exec().trace(Some(int.clone())).interpret().unwrap();
+    exec().trace(Some(tra.clone())).transpile().unwrap();
+    let int = std::fs::read_to_string(int).unwrap();
+    assert_eq!(int, std::fs::read_to_string(tra).unwrap());
+    int
+}
+
+/// Number of instructions the debugger runs before the program finishes.
+fn steps(exec: Exec) -> u64 {
+    let mut dbg = exec.debugger().unwrap();
+    while dbg.step().unwrap() != Event::Finished {}
+    dbg.steps()
+}
+
+#[test]
+fn trace() {
+    let input = Some(String::from("\u{2}"));
+    let int = trace_both("plain", || Exec::prog(",[>+<-]>+#.").input(input.clone()));
+    assert_eq!(
+        int.lines().take(5).collect::<Vec<_>>(),
+        vec!["0 1 0 0 0 2", "1 2 0 2 0 2", "2 3 0 2 1 0", "3 4 1 0 1 1", "4 5 1 1 0 2"]
+    );
+    assert_eq!(int.lines().count(), 15);
+
+    let missing = std::env::temp_dir().join("bf-lib-missing").join("trace");
+    let exec = || Exec::prog("+").trace(Some(missing.clone()));
+    assert!(matches!(exec().interpret(), Err(Error::Io(_))));
+    assert!(matches!(exec().transpile(), Err(Error::Io(_))));
+
+    let exec = || Exec::prog(COUNTDOWN).procedures(true);
+    let int = trace_both("procedures", exec);
+    assert_eq!(int.lines().count() as u64, steps(exec()));
+
+    // every step of every thread, in the order they run
+    let path = std::env::temp_dir().join("bf-lib-trace-fork");
+    let exec = || Exec::prog(RACE).fork(true);
+    exec().trace(Some(path.clone())).interpret().unwrap();
+    let int = std::fs::read_to_string(path).unwrap();
+    assert_eq!(int.lines().count() as u64, steps(exec()));
+}
+
+#[test]
+fn profile() {
+    let profile = Exec::prog("++\n[>+++[>+<-]<-]\n>>.").profile().unwrap();
+    assert_eq!(profile.output, "\u{6}");
+    assert_eq!(profile.instructions[0], (Span { start: 0, end: 2 }, 1));
+    let outer = &profile.loops[0];
+    assert_eq!(outer.span, Span { start: 3, end: 17 });
+    assert_eq!((outer.entries, outer.iterations), (1, 2));
+    let inner = &profile.loops[1];
+    assert_eq!((inner.entries, inner.iterations), (2, 6));
+    assert!(outer.time >= inner.time);
+    let report = profile.report();
+    assert!(report.contains("   43 | [>+++[>+<-]<-]"));
+    assert!(report.contains("line 2: [>+<-]"));
+}
+
+#[cfg(test)]
+mod snapshot {
+    use super::{COUNTDOWN, RACE, RACE_OUTPUT};
+    use crate::{Debugger, Error, Exec, Snapshot};
+
+    const PROG: &str = ",[>+++<-]>[>+>+<<-]>.>.";
+
+    #[test]
+    fn resume() {
+        let path = std::env::temp_dir().join("bf-lib-test.snap");
+        let expected = Exec::prog(PROG)
+            .input(Some(String::from("\u{5}")))
+            .interpret()
+            .unwrap();
+        let mut snapshot = None;
+        for steps in (7..).step_by(7) {
+            let exec = match snapshot.take() {
+                Some(s) => Exec::resume(s),
+                None => Exec::prog(PROG).input(Some(String::from("\u{5}"))),
+            };
+            match exec.max_steps(steps).checkpoint(Some(path.clone())).interpret() {
+                Ok(out) => {
+                    assert_eq!(out, expected);
+                    break;
+                }
+                Err(Error::StepLimitExceeded) => {
+                    let s = Snapshot::load(&path).unwrap();
+                    assert_eq!(s.steps(), steps);
+                    snapshot = Some(s);
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn checkpoint() {
+        let path = std::env::temp_dir().join("bf-lib-test-run.snap");
+        let _ = std::fs::remove_file(&path);
+        let res = Exec::prog("+[]")
+            .timeout(Some(std::time::Duration::from_millis(100)))
+            .checkpoint(Some(path.clone()))
+            .run();
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(Snapshot::load(&path).unwrap().steps() > 0);
+        let res = Exec::prog("+").checkpoint(Some(path)).transpile();
+        assert!(matches!(res, Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn from_debugger() {
+        let mut dbg = Exec::prog(PROG)
+            .input(Some(String::from("\u{2}")))
+            .debugger()
+            .unwrap();
+        for _ in 0..10 {
+            dbg.step().unwrap();
+        }
+        let snapshot = round_trip(&dbg);
+        assert!(matches!(
+            Exec::resume(snapshot.clone()).transpile(),
+            Err(Error::Unsupported(_))
+        ));
+        assert_eq!(Exec::resume(snapshot.clone()).run().unwrap(), "\u{6}\u{6}");
+        let mut buf = Vec::new();
+        snapshot.write(&mut buf).unwrap();
+        assert!(Snapshot::read(&mut &buf[..20]).is_err());
+    }
+
+    #[test]
+    fn procedures() {
+        let mut dbg = Exec::prog(COUNTDOWN).procedures(true).debugger().unwrap();
+        // in the second call
+        for _ in 0..12 {
+            dbg.step().unwrap();
+        }
+        let snapshot = round_trip(&dbg);
+        assert_eq!(Exec::resume(snapshot).interpret().unwrap(), "\u{3}\u{2}\u{1}");
+    }
+
+    #[test]
+    fn threads() {
+        let mut dbg = Exec::prog(RACE).fork(true).debugger().unwrap();
+        for _ in 0..9 {
+            dbg.step().unwrap();
+        }
+        let snapshot = round_trip(&dbg);
+        assert_eq!(Exec::resume(snapshot.clone()).interpret().unwrap(), RACE_OUTPUT);
+        assert!(matches!(
+            Exec::resume(snapshot).real_threads(true).interpret(),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn mismatched_calls() {
+        let dbg = Exec::prog("+(+)").procedures(true).debugger().unwrap();
+        // in the procedure without a call to return from
+        let mut snapshot = dbg.snapshot();
+        snapshot.pc = 2;
+        assert!(matches!(Exec::resume(snapshot).interpret(), Err(Error::InvalidSnapshot)));
+        // returning to something that isn't a call
+        let mut snapshot = dbg.snapshot();
+        snapshot.calls = vec![0];
+        assert!(matches!(Exec::resume(snapshot).interpret(), Err(Error::InvalidSnapshot)));
+    }
+
+    /// Writes the snapshot of the debugger and reads it back.
+    fn round_trip(dbg: &Debugger) -> Snapshot {
+        let mut buf = Vec::new();
+        dbg.snapshot().write(&mut buf).unwrap();
+        let snapshot = Snapshot::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(snapshot, dbg.snapshot());
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod debugger {
+    use super::{COUNTDOWN, RACE, RACE_OUTPUT};
+    use crate::{Event, Exec, Span, Watch};
+
+    #[test]
+    fn step() {
+        let mut dbg = Exec::prog(",>+++.")
+            .input(Some(String::from("a")))
+            .debugger()
+            .unwrap();
+        assert_eq!(dbg.step().unwrap(), Event::Step);
+        assert_eq!(dbg.input_cursor(), 1);
+        assert_eq!(dbg.span(), Some(Span { start: 1, end: 2 }));
+        dbg.step().unwrap();
+        dbg.step().unwrap();
+        assert_eq!(dbg.pointer(), 1);
+        assert_eq!(&dbg.memory()[..2], b"a\x03");
+        assert_eq!(dbg.step().unwrap(), Event::Finished);
+        assert_eq!(dbg.output(), "\x03");
+        assert_eq!(dbg.steps(), 4);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut dbg = Exec::prog("+++[-] comment . ").debugger().unwrap();
+        dbg.add_breakpoint(4);
+        dbg.add_breakpoint(8);
+        let sub = Event::Breakpoint(Span { start: 4, end: 5 });
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), sub);
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), sub);
+        assert_eq!(dbg.memory()[0], 2);
+        assert!(dbg.remove_breakpoint(4));
+        let print = Event::Breakpoint(Span { start: 15, end: 16 });
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), print);
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), Event::Finished);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut dbg = Exec::prog(",.>,.")
+            .input(Some(String::from("ab")))
+            .debugger()
+            .unwrap();
+        assert!(!dbg.step_back());
+        dbg.step().unwrap();
+        dbg.record_history(true);
+        while !dbg.finished() {
+            dbg.step().unwrap();
+        }
+        assert_eq!(dbg.output(), "ab");
+        assert!(dbg.step_back());
+        assert_eq!((dbg.output(), dbg.memory()[1]), ("a", b'b'));
+        assert!(dbg.step_back());
+        assert_eq!((dbg.input_cursor(), dbg.memory()[1]), (1, 0));
+        dbg.rewind_to(1).unwrap();
+        assert_eq!((dbg.pointer(), dbg.output(), dbg.steps()), (0, "", 1));
+        assert!(dbg.rewind_to(0).is_err());
+        dbg.rewind_to(10).unwrap();
+        assert!(dbg.finished());
+        assert_eq!(dbg.output(), "ab");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut dbg = Exec::prog(">>+<<++[>>>+<<<-]").debugger().unwrap();
+        dbg.add_watch(Watch::Changed(2));
+        dbg.add_watch(Watch::PointerPast(2));
+        let changed = Event::Watchpoint {
+            watch: Watch::Changed(2),
+            span: Span { start: 2, end: 3 },
+        };
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), changed);
+        assert_eq!(dbg.pointer(), 2);
+        let past = Event::Watchpoint {
+            watch: Watch::PointerPast(2),
+            span: Span { start: 8, end: 11 },
+        };
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), past);
+        assert_eq!(dbg.memory()[0], 2);
+        assert!(dbg.remove_watch(Watch::PointerPast(2)));
+        assert!(!dbg.remove_watch(Watch::PointerPast(2)));
+        dbg.add_watch(Watch::Equals(3, 2));
+        assert!(matches!(
+            dbg.run_until_breakpoint().unwrap(),
+            Event::Watchpoint { watch: Watch::Equals(3, 2), .. }
+        ));
+        assert_eq!(dbg.watches(), &[Watch::Changed(2), Watch::Equals(3, 2)]);
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), Event::Finished);
+        assert_eq!(&dbg.memory()[..4], &[0, 0, 1, 2]);
+
+        // the pointer of the thread that moved, not of the one running next
+        let mut dbg = Exec::prog("Y[>>>>+[]]+++").fork(true).debugger().unwrap();
+        dbg.add_watch(Watch::PointerPast(3));
+        let past = Event::Watchpoint {
+            watch: Watch::PointerPast(3),
+            span: Span { start: 2, end: 6 },
+        };
+        assert_eq!(dbg.run_until_breakpoint().unwrap(), past);
+    }
+
+    #[test]
+    fn rewind_calls() {
+        let mut dbg = Exec::prog(COUNTDOWN).procedures(true).debugger().unwrap();
+        dbg.record_history(true);
+        dbg.rewind_to(12).unwrap();
+        // before the procedure is defined
+        dbg.rewind_to(1).unwrap();
+        assert_eq!(dbg.memory()[0], 1);
+        dbg.rewind_to(100).unwrap();
+        assert!(dbg.finished());
+        assert_eq!(dbg.output(), "\u{3}\u{2}\u{1}");
+    }
+
+    #[test]
+    fn rewind_threads() {
+        let mut dbg = Exec::prog(RACE).fork(true).debugger().unwrap();
+        dbg.record_history(true);
+        dbg.rewind_to(9).unwrap();
+        // right after the fork
+        dbg.rewind_to(1).unwrap();
+        assert_eq!(dbg.memory()[..3], [0, 1, 0]);
+        dbg.rewind_to(100).unwrap();
+        assert!(dbg.finished());
+        assert_eq!(dbg.output(), RACE_OUTPUT);
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod asynchronous {
+    use crate::{CancelToken, Error, Exec};
+    use std::{
+        future::Future,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn block_on<F: Future + Send + 'static>(f: F) -> F::Output
+    where
+        F::Output: Send,
+    {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async { tokio::spawn(f).await.unwrap() })
+    }
+
+    #[test]
+    fn interpret() {
+        let res = block_on(Exec::prog("+++++[>++++++++++<-]>-.").interpret_async());
+        assert_eq!(res.unwrap(), String::from("1"));
+    }
+
+    #[test]
+    fn transpile() {
+        let res = block_on(
+            Exec::prog(",.")
+                .input(Some(String::from("a")))
+                .transpile_async(),
+        );
+        assert_eq!(res.unwrap(), String::from("a"));
+    }
+
+    #[test]
+    fn timeout() {
+        let res = block_on(
+            Exec::prog("+[]")
+                .timeout(Some(Duration::from_millis(100)))
+                .interpret_async(),
+        );
+        assert!(matches!(res, Err(Error::Timeout)));
+        let res = block_on(
+            Exec::prog("+[]")
+                .timeout(Some(Duration::from_millis(100)))
+                .transpile_async(),
+        );
+        assert!(matches!(res, Err(Error::Timeout)));
+
+        let count = Arc::new(Mutex::new(0));
+        let sink = count.clone();
+        let res = block_on(
+            Exec::prog("+#[]")
+                .on_debug(move |_| *sink.lock().unwrap() += 1)
+                .timeout(Some(Duration::from_millis(300)))
+                .transpile_async(),
+        );
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert_eq!(*count.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn cancel() {
+        let token = CancelToken::new();
+        let handle = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            handle.cancel();
+        });
+        let res = block_on(Exec::prog("+[]").cancel(token).transpile_async());
+        assert!(matches!(res, Err(Error::Cancelled)));
+
+        let token = CancelToken::new();
+        token.cancel();
+        let res = block_on(Exec::prog("+.").cancel(token).transpile_async());
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
 }